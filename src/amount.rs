@@ -0,0 +1,219 @@
+use serde::{Deserialize, Deserializer, Serialize};
+use std::convert::TryFrom;
+
+// Non-negative amount types with overflow checked arithmetic
+// Balances can go below zero, so they stay plain i32,
+// but they are only changed through credit / debit
+macro_rules! amount_type {
+  ($name:ident, $label:expr) => {
+    #[derive(
+      Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default,
+    )]
+    #[serde(transparent)]
+    pub struct $name(i32);
+
+    impl $name {
+      pub fn new(value: i32) -> Result<Self, String> {
+        if value < 0 {
+          return Err(format!("Hibás {}: {}. Nem lehet negatív!", $label, value));
+        }
+        Ok(Self(value))
+      }
+      pub fn zero() -> Self {
+        Self(0)
+      }
+      pub fn value(&self) -> i32 {
+        self.0
+      }
+      pub fn is_zero(&self) -> bool {
+        self.0 == 0
+      }
+      pub fn checked_add(self, other: Self) -> Result<Self, String> {
+        self
+          .0
+          .checked_add(other.0)
+          .map(Self)
+          .ok_or(format!("Túl nagy {}!", $label))
+      }
+      pub fn checked_sub(self, other: Self) -> Result<Self, String> {
+        Self::new(self.0 - other.0)
+      }
+      pub fn checked_sum<I: Iterator<Item = Self>>(iter: I) -> Result<Self, String> {
+        iter.fold(Ok(Self::zero()), |acc, a| acc?.checked_add(a))
+      }
+      // Add to a signed balance
+      pub fn credit(self, balance: i32) -> Result<i32, String> {
+        balance
+          .checked_add(self.0)
+          .ok_or(format!("Túl nagy egyenleg ({})!", $label))
+      }
+      // Take from a signed balance
+      pub fn debit(self, balance: i32) -> Result<i32, String> {
+        balance
+          .checked_sub(self.0)
+          .ok_or(format!("Túl kicsi egyenleg ({})!", $label))
+      }
+    }
+
+    impl TryFrom<u32> for $name {
+      type Error = String;
+      fn try_from(value: u32) -> Result<Self, Self::Error> {
+        i32::try_from(value)
+          .map(Self)
+          .map_err(|_| format!("Túl nagy {}: {}", $label, value))
+      }
+    }
+
+    impl std::fmt::Display for $name {
+      fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+      }
+    }
+  };
+}
+
+// Loyalty points
+amount_type!(Points, "pontszám");
+
+// Gross money amount
+amount_type!(Money, "összeg");
+
+// Rounding of fractional points
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum RoundingPolicy {
+  Floor,
+  #[default]
+  HalfUp, // Same as the former f32 rounding
+  HalfEven, // Banker's rounding
+}
+
+impl RoundingPolicy {
+  // Divide non-negative numerator by positive denominator
+  pub fn divide(&self, numerator: i64, denominator: i64) -> i64 {
+    let quotient = numerator / denominator;
+    let remainder = numerator % denominator;
+    match self {
+      RoundingPolicy::Floor => quotient,
+      RoundingPolicy::HalfUp if remainder * 2 >= denominator => quotient + 1,
+      RoundingPolicy::HalfUp => quotient,
+      RoundingPolicy::HalfEven => match (remainder * 2).cmp(&denominator) {
+        std::cmp::Ordering::Less => quotient,
+        std::cmp::Ordering::Greater => quotient + 1,
+        std::cmp::Ordering::Equal => quotient + quotient % 2,
+      },
+    }
+  }
+
+  // Given percentage of the points; 50 => half
+  pub fn percentage(&self, points: Points, percentage: u32) -> Result<Points, String> {
+    let value = self.divide(points.value() as i64 * percentage as i64, 100);
+    Points::new(i32::try_from(value).map_err(|_| "Túl nagy pontszám!".to_string())?)
+  }
+
+  // Points in proportion of part / whole
+  pub fn proportion(&self, points: Points, part: Money, whole: Money) -> Result<Points, String> {
+    if whole.is_zero() {
+      return Ok(Points::zero());
+    }
+    let value = self.divide(
+      points.value() as i64 * part.value() as i64,
+      whole.value() as i64,
+    );
+    Points::new(i32::try_from(value).map_err(|_| "Túl nagy pontszám!".to_string())?)
+  }
+}
+
+// Earn rate in basis points; 100 => 1%
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(transparent)]
+pub struct Rate(u32);
+
+// Rates were stored as f32 fractions before (0.02 => 2%),
+// in earn transactions and in the tier table;
+// both forms are accepted
+impl<'de> Deserialize<'de> for Rate {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredRate {
+      BasisPoints(u32),
+      Fraction(f32),
+    }
+    match StoredRate::deserialize(deserializer)? {
+      StoredRate::BasisPoints(basis_points) => Ok(Self(basis_points)),
+      StoredRate::Fraction(fraction) if fraction.is_finite() && fraction >= 0.0 => {
+        Ok(Self((fraction as f64 * Self::MAX as f64).round() as u32))
+      }
+      StoredRate::Fraction(fraction) => Err(serde::de::Error::custom(format!(
+        "Invalid earn rate: {}",
+        fraction
+      ))),
+    }
+  }
+}
+
+impl Rate {
+  pub const MAX: u32 = 10_000;
+
+  pub fn from_basis_points(basis_points: u32) -> Self {
+    Self(basis_points)
+  }
+  pub fn basis_points(&self) -> u32 {
+    self.0
+  }
+  // Points earned on the given amount
+  pub fn apply(&self, amount: Money, rounding: RoundingPolicy) -> Result<Points, String> {
+    let value = rounding.divide(amount.value() as i64 * self.0 as i64, Self::MAX as i64);
+    Points::new(i32::try_from(value).map_err(|_| "Túl nagy pontszám!".to_string())?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn test_amount() {
+    assert!(Points::new(-1).is_err());
+    assert_eq!(Points::new(5).unwrap().value(), 5);
+    assert!(Points::new(i32::MAX)
+      .unwrap()
+      .checked_add(Points::new(1).unwrap())
+      .is_err());
+    assert!(Points::new(1)
+      .unwrap()
+      .checked_sub(Points::new(2).unwrap())
+      .is_err());
+    assert!(Money::try_from(u32::MAX).is_err());
+    assert_eq!(Points::new(3).unwrap().debit(1).unwrap(), -2);
+    assert!(Points::new(1).unwrap().debit(i32::MIN).is_err());
+  }
+  #[test]
+  fn test_rounding() {
+    let rate = Rate::from_basis_points(250); // 2.5%
+    let apply = |amount, rounding| {
+      rate
+        .apply(Money::new(amount).unwrap(), rounding)
+        .unwrap()
+        .value()
+    };
+    // 2.5% of 100 => 2.5
+    assert_eq!(apply(100, RoundingPolicy::Floor), 2);
+    assert_eq!(apply(100, RoundingPolicy::HalfUp), 3);
+    assert_eq!(apply(100, RoundingPolicy::HalfEven), 2);
+    // 2.5% of 140 => 3.5
+    assert_eq!(apply(140, RoundingPolicy::HalfEven), 4);
+    // 2.5% of 99 => 2.475
+    assert_eq!(apply(99, RoundingPolicy::HalfUp), 2);
+    // No drift on large baskets
+    assert_eq!(apply(i32::MAX, RoundingPolicy::Floor), 53_687_091);
+  }
+  #[test]
+  fn test_stored_rate() {
+    let rate = |json| serde_json::from_str::<Rate>(json).map(|r| r.basis_points());
+    assert_eq!(rate("250").unwrap(), 250);
+    // Former f32 fractions
+    assert_eq!(rate("0.02").unwrap(), 200);
+    assert_eq!(rate("0.04").unwrap(), 400);
+    assert!(rate("-0.02").is_err());
+  }
+}
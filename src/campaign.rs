@@ -0,0 +1,184 @@
+use crate::amount::{Points, RoundingPolicy};
+use crate::loyalty::LoyaltyLevel;
+use chrono::{DateTime, Datelike, NaiveDate, Utc, Weekday};
+use packman::VecPackMember;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum CampaignReward {
+  // Points earned on the purchase multiplied, in percent; 200 => double points
+  Multiplier { percentage: u32 },
+  // Flat bonus points per purchase
+  Bonus { points: i32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Campaign {
+  pub campaign_id: Uuid,
+  pub name: String,
+  pub start_date: NaiveDate, // First day of the campaign
+  pub end_date: NaiveDate,   // Last day of the campaign
+  // Days of the week the campaign runs on; empty => every day
+  pub weekdays: Vec<Weekday>,
+  // Tiers the campaign applies to; empty => every tier
+  pub loyalty_levels: Vec<LoyaltyLevel>,
+  pub reward: CampaignReward,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl Campaign {
+  pub fn new(
+    name: String,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    weekdays: Vec<Weekday>,
+    loyalty_levels: Vec<LoyaltyLevel>,
+    reward: CampaignReward,
+    created_by: u32,
+  ) -> Result<Self, String> {
+    if name.trim().is_empty() {
+      return Err("A kampány neve nem lehet üres!".to_string());
+    }
+    if end_date < start_date {
+      return Err("A kampány vége nem lehet korábbi, mint a kezdete!".to_string());
+    }
+    match reward {
+      CampaignReward::Multiplier { percentage } if percentage < 100 => {
+        return Err("A kampány szorzója nem lehet 100% alatt!".to_string())
+      }
+      CampaignReward::Bonus { points } if points < 0 => {
+        return Err("A kampány bónusza nem lehet negatív!".to_string())
+      }
+      _ => (),
+    }
+    Ok(Self {
+      campaign_id: Uuid::new_v4(),
+      name,
+      start_date,
+      end_date,
+      weekdays,
+      loyalty_levels,
+      reward,
+      created_by,
+      created_at: Utc::now(),
+    })
+  }
+
+  /// Check if the campaign applies to a purchase
+  /// on the given day with the given tier
+  pub fn is_active(&self, day: NaiveDate, loyalty_level: &LoyaltyLevel) -> bool {
+    self.start_date <= day
+      && day <= self.end_date
+      && (self.weekdays.is_empty() || self.weekdays.contains(&day.weekday()))
+      && (self.loyalty_levels.is_empty() || self.loyalty_levels.contains(loyalty_level))
+  }
+
+  /// Stop the campaign, it does not apply from the given day
+  pub fn close(&mut self, day: NaiveDate) -> Result<&Self, String> {
+    if self.end_date < day {
+      return Err("A kampány már véget ért!".to_string());
+    }
+    self.end_date = day.pred();
+    Ok(self)
+  }
+
+  /// Extra points on top of the points earned on a purchase
+  pub fn extra_points(
+    &self,
+    points_earned: Points,
+    rounding: RoundingPolicy,
+  ) -> Result<Points, String> {
+    match self.reward {
+      CampaignReward::Multiplier { percentage } => {
+        rounding.percentage(points_earned, percentage.saturating_sub(100))
+      }
+      CampaignReward::Bonus { points } => Points::new(points),
+    }
+  }
+}
+
+impl VecPackMember for Campaign {
+  type Out = Uuid;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.campaign_id
+  }
+}
+
+/// Active campaign giving the most extra points, with its extra points
+/// Campaigns do not add up, only one is applied per purchase
+pub fn best_campaign<'a>(
+  campaigns: &'a [Campaign],
+  day: NaiveDate,
+  loyalty_level: &LoyaltyLevel,
+  points_earned: Points,
+  rounding: RoundingPolicy,
+) -> Result<Option<(&'a Campaign, Points)>, String> {
+  let mut best: Option<(&Campaign, Points)> = None;
+  for campaign in campaigns.iter().filter(|c| c.is_active(day, loyalty_level)) {
+    let extra = campaign.extra_points(points_earned, rounding)?;
+    if extra.is_zero() {
+      continue;
+    }
+    if best.is_none_or(|(_, best_extra)| extra > best_extra) {
+      best = Some((campaign, extra));
+    }
+  }
+  Ok(best)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[test]
+  fn test_best_campaign() {
+    let day = NaiveDate::from_ymd(2021, 6, 5); // Saturday
+    let gold = LoyaltyLevel::new("GOLD");
+    let weekend = Campaign::new(
+      "Double points weekend".to_string(),
+      day,
+      day.succ(),
+      vec![Weekday::Sat, Weekday::Sun],
+      Vec::new(),
+      CampaignReward::Multiplier { percentage: 200 },
+      0,
+    )
+    .unwrap();
+    let gold_bonus = Campaign::new(
+      "Gold bonus".to_string(),
+      day.pred(),
+      day,
+      Vec::new(),
+      vec![gold.clone()],
+      CampaignReward::Bonus { points: 150 },
+      0,
+    )
+    .unwrap();
+    let campaigns = vec![weekend, gold_bonus];
+    let points = Points::new(100).unwrap();
+    let best = |day: NaiveDate, level: &LoyaltyLevel| {
+      best_campaign(&campaigns, day, level, points, RoundingPolicy::HalfUp)
+        .unwrap()
+        .map(|(c, extra)| (c.name.clone(), extra.value()))
+    };
+    assert_eq!(best(day, &gold), Some(("Gold bonus".to_string(), 150)));
+    assert_eq!(
+      best(day, &LoyaltyLevel::new("L1")),
+      Some(("Double points weekend".to_string(), 100))
+    );
+    // Monday after the weekend
+    assert_eq!(best(day.succ().succ(), &gold), None);
+    assert!(Campaign::new(
+      "Wrong".to_string(),
+      day,
+      day.pred(),
+      Vec::new(),
+      Vec::new(),
+      CampaignReward::Bonus { points: 1 },
+      0
+    )
+    .is_err());
+  }
+}
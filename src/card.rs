@@ -0,0 +1,482 @@
+use crate::config::{CardRange, LoyaltyConfig};
+use crate::loyalty::{self, Account, AccountExt, CardState};
+use chrono::{DateTime, Utc};
+use packman::VecPackMember;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+// Most cards allocated at once
+pub const MAX_BATCH_SIZE: u32 = 100_000;
+
+// Card ID => account ID of every card ever issued
+// A card ID can belong to one account only,
+// retired cards are kept so their IDs are not reused
+// Cards of erased accounts are kept with the nil account ID
+#[derive(Default)]
+pub struct CardIndex {
+  cards: HashMap<String, Uuid>,
+}
+
+// Card ID held by more than one account
+#[derive(Debug, PartialEq)]
+pub struct DuplicatedCard {
+  pub card_id: String,
+  pub account_ids: Vec<Uuid>,
+}
+
+impl CardIndex {
+  /// Build the index from the stored accounts,
+  /// with the card IDs found on more than one account
+  /// Duplicates are indexed by the first account holding them
+  pub fn build<'a, I>(accounts: I) -> (Self, Vec<DuplicatedCard>)
+  where
+    I: Iterator<Item = &'a Account>,
+  {
+    let mut index = Self::default();
+    let mut duplicates: Vec<DuplicatedCard> = Vec::new();
+    for account in accounts {
+      for card in account.cards.iter() {
+        match index.cards.get(&card.card_id) {
+          None => {
+            index.insert(&card.card_id, account.account_id);
+          }
+          Some(account_id) if *account_id == account.account_id => (),
+          Some(account_id) => match duplicates.iter_mut().find(|d| d.card_id == card.card_id) {
+            Some(duplicate) => duplicate.account_ids.push(account.account_id),
+            None => duplicates.push(DuplicatedCard {
+              card_id: card.card_id.clone(),
+              account_ids: vec![*account_id, account.account_id],
+            }),
+          },
+        }
+      }
+    }
+    (index, duplicates)
+  }
+
+  /// Account holding the given card
+  pub fn find(&self, card_id: &str) -> Option<Uuid> {
+    self
+      .cards
+      .get(card_id)
+      .filter(|account_id| !account_id.is_nil())
+      .cloned()
+  }
+
+  /// Check if the card ID was ever issued,
+  /// including cards of erased accounts
+  pub fn is_used(&self, card_id: &str) -> bool {
+    self.cards.contains_key(card_id)
+  }
+
+  /// Check if the card ID can be issued to the given account
+  pub fn is_available(&self, card_id: &str, account_id: Uuid) -> bool {
+    match self.cards.get(card_id) {
+      Some(holder) => !holder.is_nil() && *holder == account_id,
+      None => true,
+    }
+  }
+
+  pub fn insert(&mut self, card_id: &str, account_id: Uuid) {
+    self.cards.insert(card_id.to_string(), account_id);
+  }
+
+  /// Keep the card ID of an erased account,
+  /// without linking it to any account
+  pub fn reserve(&mut self, card_id: &str) {
+    self.insert(card_id, Uuid::nil());
+  }
+}
+
+// Card ID of an erased account
+// Stored apart from the account, so the ID is never reused
+// but cannot be linked to the customer anymore
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ErasedCard {
+  pub card_id: String,
+}
+
+impl VecPackMember for ErasedCard {
+  type Out = String;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.card_id
+  }
+}
+
+/// Luhn check digit of the given digits
+/// Appended to them gives a number passing the Luhn check
+pub fn luhn_check_digit(digits: &str) -> Result<u32, String> {
+  let mut sum = 0;
+  // Rightmost digit is doubled, as the check digit comes after it
+  for (i, c) in digits.chars().rev().enumerate() {
+    let digit = c
+      .to_digit(10)
+      .ok_or(format!("Nem megfelelő kártyaszám: {}", digits))?;
+    sum += match i % 2 {
+      0 if digit * 2 > 9 => digit * 2 - 9,
+      0 => digit * 2,
+      _ => digit,
+    };
+  }
+  Ok((10 - sum % 10) % 10)
+}
+
+/// Card number of the given serial in the range
+pub fn card_number(range: &CardRange, serial: u64) -> Result<String, String> {
+  let digits = format!(
+    "{}{:0width$}",
+    range.prefix,
+    serial,
+    width = range.serial_digits as usize
+  );
+  let check_digit = luhn_check_digit(&digits)?;
+  Ok(format!("{}{}", digits, check_digit))
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BatchStatus {
+  Allocated, // Numbers reserved, not printed yet
+  Printed,   // Cards printed, not handed out yet
+  Activated, // Cards handed out, can be used
+}
+
+impl std::str::FromStr for BatchStatus {
+  type Err = String;
+  fn from_str(str: &str) -> Result<Self, Self::Err> {
+    [
+      BatchStatus::Allocated,
+      BatchStatus::Printed,
+      BatchStatus::Activated,
+    ]
+    .iter()
+    .find(|s| format!("{:?}", s).eq_ignore_ascii_case(str))
+    .cloned()
+    .ok_or(format!(
+      "Nem megfelelő kártyacsomag állapot! Lehetséges értékek: {}",
+      "Allocated, Printed, Activated"
+    ))
+  }
+}
+
+// Card numbers allocated together, printed and handed out together
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CardBatch {
+  pub batch_id: Uuid,
+  pub prefix: String, // Range the numbers were taken from
+  pub serial_digits: u32,
+  pub first_serial: u64,
+  pub last_serial: u64, // Numbers already in use in between are skipped
+  pub card_ids: Vec<String>,
+  pub status: BatchStatus,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+  pub printed_at: Option<DateTime<Utc>>,
+  pub activated_at: Option<DateTime<Utc>>,
+}
+
+impl CardBatch {
+  /// Allocate the given number of new card numbers
+  /// from the first range having enough of them
+  /// Serials of earlier batches are never reused,
+  /// and numbers already held by accounts or earlier batches are skipped
+  /// (ranges can be reconfigured to overlap them)
+  pub fn allocate(
+    ranges: &[CardRange],
+    batches: &[CardBatch],
+    card_index: &CardIndex,
+    count: u32,
+    created_by: u32,
+  ) -> Result<Self, String> {
+    if count == 0 || count > MAX_BATCH_SIZE {
+      return Err(format!(
+        "A kártyacsomag mérete 1 és {} között lehet!",
+        MAX_BATCH_SIZE
+      ));
+    }
+    // Numbers of the earlier batches, looked up for every candidate
+    let batched = batches
+      .iter()
+      .flat_map(|b| b.card_ids.iter().map(|id| id.as_str()))
+      .collect::<HashSet<&str>>();
+    for range in ranges {
+      let next_serial = batches
+        .iter()
+        .filter(|b| b.prefix == range.prefix && b.serial_digits == range.serial_digits)
+        .map(|b| b.last_serial + 1)
+        .max()
+        .map_or(range.first_serial, |serial| {
+          std::cmp::max(serial, range.first_serial)
+        });
+      let mut card_ids: Vec<String> = Vec::new();
+      let mut serial = next_serial;
+      while serial <= range.last_serial && card_ids.len() < count as usize {
+        let card_id = card_number(range, serial)?;
+        if !card_index.is_used(&card_id) && !batched.contains(card_id.as_str()) {
+          card_ids.push(card_id);
+        }
+        serial += 1;
+      }
+      if card_ids.len() == count as usize {
+        return Ok(Self {
+          batch_id: Uuid::new_v4(),
+          prefix: range.prefix.clone(),
+          serial_digits: range.serial_digits,
+          first_serial: next_serial,
+          last_serial: serial - 1,
+          card_ids,
+          status: BatchStatus::Allocated,
+          created_by,
+          created_at: Utc::now(),
+          printed_at: None,
+          activated_at: None,
+        });
+      }
+    }
+    Err("Nincs elég szabad kártyaszám a beállított tartományokban!".to_string())
+  }
+
+  pub fn contains(&self, card_id: &str) -> bool {
+    self.card_ids.iter().any(|id| id == card_id)
+  }
+
+  /// Check if the card can be activated,
+  /// i.e. it is part of the batch and the batch is handed out
+  pub fn is_activatable(&self, card_id: &str) -> bool {
+    self.status == BatchStatus::Activated && self.contains(card_id)
+  }
+
+  /// Move the batch forward to the given status
+  pub fn set_status(&mut self, status: BatchStatus) -> Result<&Self, String> {
+    match (self.status, status) {
+      (BatchStatus::Allocated, BatchStatus::Printed) => self.printed_at = Some(Utc::now()),
+      (BatchStatus::Printed, BatchStatus::Activated) => self.activated_at = Some(Utc::now()),
+      _ => {
+        return Err(format!(
+          "A kártyacsomag nem állítható {:?} állapotból {:?} állapotba!",
+          self.status, status
+        ))
+      }
+    }
+    self.status = status;
+    Ok(self)
+  }
+}
+
+/// Check if the card can be bound to an account
+/// Pre-printed cards only when their batch is handed out
+pub fn check_batch_card<'a, I>(mut batches: I, card_id: &str) -> Result<(), String>
+where
+  I: Iterator<Item = &'a CardBatch>,
+{
+  match batches.find(|b| b.contains(card_id)) {
+    Some(batch) if !batch.is_activatable(card_id) => {
+      Err("A megadott kártya még nincs kiadva!".to_string())
+    }
+    _ => Ok(()),
+  }
+}
+
+impl VecPackMember for CardBatch {
+  type Out = Uuid;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.batch_id
+  }
+}
+
+/// Bind a pre-printed card to the account
+/// If the card was used before on an anonymous account (holder),
+/// its points and turnover are carried over and the card moves
+/// Either both accounts change or none of them
+pub fn activate_card(
+  card_id: &str,
+  account: &mut Account,
+  holder: Option<&mut Account>,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<(), String> {
+  let mut target = account.clone();
+  match holder {
+    Some(holder) => {
+      let mut source = holder.clone();
+      match source.get_card(card_id).map(|c| c.state) {
+        Some(CardState::Issued) | Some(CardState::Active) => (),
+        _ => return Err("A megadott kártya nem aktiválható!".to_string()),
+      }
+      loyalty::carry_over(&mut source, &mut target, config, created_by)?;
+      // Card belongs to one account only
+      source.cards.retain(|c| c.card_id != card_id);
+      target.set_card(card_id.to_string())?;
+      *holder = source;
+    }
+    None => {
+      target.set_card(card_id.to_string())?;
+    }
+  }
+  *account = target;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::loyalty::{AccountExt, LoyaltyLevel};
+  use gzlib::id::LuhnCheck;
+  #[test]
+  fn test_card_index() {
+    let mut accounts = (0..3)
+      .map(|customer_id| {
+        Account::new(
+          customer_id,
+          Utc::today().naive_local(),
+          LoyaltyLevel::new("L1"),
+          0,
+        )
+      })
+      .collect::<Vec<Account>>();
+    accounts[0].set_card("79927398713".to_string()).unwrap();
+    accounts[1]
+      .set_card("4111111111111111".to_string())
+      .unwrap();
+    accounts[2].set_card("79927398713".to_string()).unwrap();
+    let (mut index, duplicates) = CardIndex::build(accounts.iter());
+    assert_eq!(
+      duplicates,
+      vec![DuplicatedCard {
+        card_id: "79927398713".to_string(),
+        account_ids: vec![accounts[0].account_id, accounts[2].account_id],
+      }]
+    );
+    assert_eq!(index.find("79927398713"), Some(accounts[0].account_id));
+    assert!(index.is_available("4111111111111111", accounts[1].account_id));
+    assert!(!index.is_available("4111111111111111", accounts[0].account_id));
+    assert!(index.is_available("0", accounts[0].account_id));
+    // Erased card is not found, and not reused either
+    index.reserve("4111111111111111");
+    assert_eq!(index.find("4111111111111111"), None);
+    assert!(index.is_used("4111111111111111"));
+    assert!(!index.is_available("4111111111111111", accounts[1].account_id));
+    // Single card of an earlier version is indexed once upgraded
+    let mut stored = serde_json::to_value(Account::new(
+      3,
+      Utc::today().naive_local(),
+      LoyaltyLevel::new("L1"),
+      0,
+    ))
+    .unwrap();
+    stored["card_id"] = serde_json::json!("5105105105105100");
+    let mut legacy: Account = serde_json::from_value(stored).unwrap();
+    legacy
+      .upgrade(&LoyaltyConfig::default(), Utc::now())
+      .unwrap();
+    let (index, _) = CardIndex::build(accounts.iter().chain(std::iter::once(&legacy)));
+    assert_eq!(index.find("5105105105105100"), Some(legacy.account_id));
+  }
+  #[test]
+  fn test_card_batch() {
+    assert_eq!(luhn_check_digit("7992739871").unwrap(), 3);
+    let ranges = vec![
+      CardRange {
+        prefix: "800".to_string(),
+        serial_digits: 4,
+        first_serial: 1,
+        last_serial: 5,
+      },
+      CardRange {
+        prefix: "801".to_string(),
+        serial_digits: 4,
+        first_serial: 1,
+        last_serial: 9999,
+      },
+    ];
+    let mut account = Account::new(0, Utc::today().naive_local(), LoyaltyLevel::new("L1"), 0);
+    account
+      .set_card(card_number(&ranges[0], 2).unwrap())
+      .unwrap();
+    let (card_index, _) = CardIndex::build(std::iter::once(&account));
+    let first = CardBatch::allocate(&ranges, &[], &card_index, 3, 0).unwrap();
+    // Number held by the account is skipped
+    assert_eq!(first.card_ids.len(), 3);
+    assert_eq!(first.card_ids[0], "80000011".to_string());
+    assert_eq!((first.first_serial, first.last_serial), (1, 4));
+    assert!(first
+      .card_ids
+      .iter()
+      .all(|id| id.clone().luhn_check().is_ok()));
+    // First range has only one number left
+    let second =
+      CardBatch::allocate(&ranges, std::slice::from_ref(&first), &card_index, 2, 0).unwrap();
+    assert_eq!(second.prefix, "801".to_string());
+    assert!(second
+      .card_ids
+      .iter()
+      .all(|id| !first.card_ids.contains(id)));
+    let mut batch = second;
+    assert!(batch.set_status(BatchStatus::Activated).is_err());
+    batch.set_status(BatchStatus::Printed).unwrap();
+    batch.set_status(BatchStatus::Activated).unwrap();
+    assert!(batch.activated_at.is_some());
+    assert!(batch.is_activatable(&batch.card_ids[0]));
+    assert!(!batch.is_activatable(&first.card_ids[0]));
+    // Cards of batches not handed out cannot be bound
+    let batches = vec![first.clone(), batch.clone()];
+    assert!(check_batch_card(batches.iter(), &batch.card_ids[0]).is_ok());
+    assert!(check_batch_card(batches.iter(), &first.card_ids[0]).is_err());
+    assert!(check_batch_card(batches.iter(), "79927398713").is_ok());
+    // Reconfigured range overlapping an earlier batch
+    let moved = vec![CardRange {
+      prefix: "80".to_string(),
+      serial_digits: 5,
+      first_serial: 1,
+      last_serial: 99999,
+    }];
+    let third = CardBatch::allocate(&moved, &batches, &card_index, 3, 0).unwrap();
+    assert!(third.card_ids.iter().all(|id| !first.contains(id)));
+  }
+  #[test]
+  fn test_activate_card() {
+    let config = LoyaltyConfig::default();
+    // Points collected on the card before registering
+    let mut anonymous = Account::new_anonymous(config.base_level(), 0);
+    anonymous.set_card("79927398713".to_string()).unwrap();
+    anonymous
+      .close_purchase(
+        loyalty::PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: crate::amount::Money::new(60_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    assert_eq!(anonymous.customer_birthdate, None);
+    let mut account = Account::new(1, Utc::today().naive_local(), config.base_level(), 0);
+    // Unknown card cannot be activated
+    assert!(activate_card("1234", &mut account, None, &config, 0).is_err());
+    activate_card(
+      "79927398713",
+      &mut account,
+      Some(&mut anonymous),
+      &config,
+      0,
+    )
+    .unwrap();
+    assert_eq!(anonymous.get_balance(), 0);
+    assert!(anonymous.cards.is_empty());
+    assert_eq!(account.get_balance(), 1200);
+    assert_eq!(account.get_yearly_gross_turnover(), 60_000);
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L2"));
+    assert_eq!(
+      account.get_active_card().unwrap().card_id,
+      "79927398713".to_string()
+    );
+    // Registered accounts are not carried over
+    let mut other = Account::new(2, Utc::today().naive_local(), config.base_level(), 0);
+    assert!(activate_card("79927398713", &mut other, Some(&mut account), &config, 0).is_err());
+    assert_eq!(account.get_balance(), 1200);
+  }
+}
@@ -0,0 +1,377 @@
+use crate::amount::{Rate, RoundingPolicy};
+use crate::loyalty::LoyaltyLevel;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tier {
+  pub id: String,               // Stored on accounts, e.g. L1
+  pub name: String,             // Display name, e.g. Bronze
+  pub qualifying_turnover: i32, // Yearly gross turnover needed to reach this tier
+  pub earn_rate: Rate,          // Basis points, e.g. 200 => 2%
+}
+
+impl Tier {
+  pub fn new(id: &str, name: &str, qualifying_turnover: i32, earn_rate_bp: u32) -> Self {
+    Self {
+      id: id.to_string(),
+      name: name.to_string(),
+      qualifying_turnover,
+      earn_rate: Rate::from_basis_points(earn_rate_bp),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequalificationConfig {
+  // Yearly requalification date; accounts are re-evaluated
+  // against the previous year's turnover on this day
+  pub month: u32,
+  pub day: u32,
+  // Downgrades take effect this many days after the requalification date;
+  // reaching the current tier again meanwhile cancels the downgrade
+  pub grace_period_days: i64,
+}
+
+impl Default for RequalificationConfig {
+  fn default() -> Self {
+    Self {
+      month: 1,
+      day: 1,
+      grace_period_days: 0,
+    }
+  }
+}
+
+impl RequalificationConfig {
+  /// Requalification moment in the given year
+  pub fn date_of_year(&self, year: i32) -> DateTime<Utc> {
+    Utc
+      .from_utc_date(&NaiveDate::from_ymd(year, self.month, self.day))
+      .and_hms(0, 0, 0)
+  }
+
+  pub fn grace_period(&self) -> Duration {
+    Duration::days(self.grace_period_days)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum BirthdayReward {
+  // Fixed bonus points
+  Bonus { points: i32 },
+  // Points earned on the purchase multiplied, in percent; 200 => double points
+  Multiplier { percentage: u32 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BirthdayConfig {
+  // No birthday reward if not set
+  pub reward: Option<BirthdayReward>,
+  // Reward is given on the first purchase
+  // within this many days from the birthday
+  pub window_days: i64,
+}
+
+impl Default for BirthdayConfig {
+  fn default() -> Self {
+    Self {
+      reward: None,
+      window_days: 7,
+    }
+  }
+}
+
+impl BirthdayConfig {
+  /// Birthday in the given year
+  /// 29th of February is celebrated on the 28th in non-leap years
+  pub fn birthday_of_year(birthdate: NaiveDate, year: i32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(year, birthdate.month(), birthdate.day())
+      .unwrap_or_else(|| NaiveDate::from_ymd(year, 2, 28))
+  }
+
+  /// Year of the birthday whose reward window contains the given day
+  /// Window can reach over new year
+  pub fn celebrated_year(&self, birthdate: NaiveDate, day: NaiveDate) -> Option<i32> {
+    vec![day.year(), day.year() - 1].into_iter().find(|year| {
+      let birthday = Self::birthday_of_year(birthdate, *year);
+      birthday <= day && day < birthday + Duration::days(self.window_days)
+    })
+  }
+}
+
+// What happens to the remaining points when an account is closed
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ClosingPolicy {
+  Forfeit, // Points are lost
+  PayOut,  // Points are paid out to the customer
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AdjustmentApprovalConfig {
+  // Manual adjustments above this many points
+  // need the approval of a second user
+  pub threshold: i32,
+  // Requests not decided in this many hours expire
+  pub timeout_hours: i64,
+}
+
+impl Default for AdjustmentApprovalConfig {
+  fn default() -> Self {
+    Self {
+      threshold: 1_000,
+      timeout_hours: 72,
+    }
+  }
+}
+
+impl AdjustmentApprovalConfig {
+  pub fn timeout(&self) -> Duration {
+    Duration::hours(self.timeout_hours)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TransferConfig {
+  // Points an account can transfer out per calendar day
+  pub daily_limit: i32,
+  // Points an account can transfer out per calendar year
+  pub yearly_limit: i32,
+}
+
+impl Default for TransferConfig {
+  fn default() -> Self {
+    Self {
+      daily_limit: 5_000,
+      yearly_limit: 50_000,
+    }
+  }
+}
+
+// Card numbers allocated by the service
+// Number = prefix + zero padded serial + Luhn check digit
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CardRange {
+  pub prefix: String,
+  pub serial_digits: u32,
+  pub first_serial: u64,
+  pub last_serial: u64,
+}
+
+impl CardRange {
+  /// Length of the card numbers in the range, with the check digit
+  pub fn number_length(&self) -> usize {
+    self.prefix.len() + self.serial_digits as usize + 1
+  }
+
+  /// First and last card number of the range
+  /// without the check digit, as numbers
+  fn bounds(&self) -> (u128, u128) {
+    let base = self.prefix.parse::<u128>().unwrap_or(0) * 10u128.pow(self.serial_digits);
+    (
+      base + self.first_serial as u128,
+      base + self.last_serial as u128,
+    )
+  }
+
+  /// Check if the two ranges can give the same card number
+  pub fn overlaps(&self, other: &CardRange) -> bool {
+    if self.number_length() != other.number_length() {
+      return false;
+    }
+    let (first, last) = self.bounds();
+    let (other_first, other_last) = other.bounds();
+    first <= other_last && other_first <= last
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoyaltyConfig {
+  // Tiers ordered by qualifying turnover, lowest first
+  pub tiers: Vec<Tier>,
+  pub requalification: RequalificationConfig,
+  // Rounding of earned and refunded points
+  pub rounding: RoundingPolicy,
+  // Earned points expire at the end of this many calendar years
+  // after the year of earning; 0 => end of the same year
+  pub point_expiry_years: i32,
+  // Reserved points are released after this many minutes
+  // if the purchase is not closed
+  pub reservation_timeout_minutes: i64,
+  // Points earned on a purchase become spendable after this many days,
+  // when the goods cannot be returned anymore; 0 => right away
+  pub pending_days: i64,
+  pub birthday: BirthdayConfig,
+  pub adjustment_approval: AdjustmentApprovalConfig,
+  pub transfer: TransferConfig,
+  // Card number ranges, used in order
+  pub card_ranges: Vec<CardRange>,
+  pub closing_policy: ClosingPolicy,
+}
+
+impl Default for LoyaltyConfig {
+  fn default() -> Self {
+    Self {
+      tiers: vec![
+        Tier::new("L1", "L1", 0, 200),
+        Tier::new("L2", "L2", 50_000, 400),
+      ],
+      requalification: RequalificationConfig::default(),
+      rounding: RoundingPolicy::default(),
+      point_expiry_years: 2,
+      reservation_timeout_minutes: 30,
+      pending_days: 0,
+      birthday: BirthdayConfig::default(),
+      adjustment_approval: AdjustmentApprovalConfig::default(),
+      transfer: TransferConfig::default(),
+      card_ranges: vec![CardRange {
+        prefix: "800".to_string(),
+        serial_digits: 9,
+        first_serial: 1,
+        last_serial: 999_999_999,
+      }],
+      closing_policy: ClosingPolicy::Forfeit,
+    }
+  }
+}
+
+impl LoyaltyConfig {
+  /// Load config from a JSON file
+  /// If the file does not exist, the default config is used
+  pub fn load(path: PathBuf) -> Result<Self, String> {
+    if !path.exists() {
+      return Self::default().validate();
+    }
+    let content = std::fs::read_to_string(&path)
+      .map_err(|e| format!("Error while reading config file {:?}: {}", path, e))?;
+    let config: LoyaltyConfig = serde_json::from_str(&content)
+      .map_err(|e| format!("Error while parsing config file {:?}: {}", path, e))?;
+    config.validate()
+  }
+
+  /// Check the tier table and sort it by qualifying turnover
+  pub fn validate(mut self) -> Result<Self, String> {
+    if self.tiers.is_empty() {
+      return Err("At least one loyalty tier must be configured".to_string());
+    }
+    for (i, tier) in self.tiers.iter().enumerate() {
+      if self.tiers[..i].iter().any(|t| t.id == tier.id) {
+        return Err(format!("Duplicated loyalty tier ID: {}", tier.id));
+      }
+      if tier.earn_rate.basis_points() > Rate::MAX {
+        return Err(format!("Earn rate above 100% for tier {}", tier.id));
+      }
+    }
+    self.tiers.sort_by_key(|tier| tier.qualifying_turnover);
+    // Must be a valid date in every year, so 29th of February is not allowed
+    let requalification = &self.requalification;
+    if NaiveDate::from_ymd_opt(2021, requalification.month, requalification.day).is_none() {
+      return Err(format!(
+        "Invalid requalification date: {}-{}",
+        requalification.month, requalification.day
+      ));
+    }
+    if requalification.grace_period_days < 0 {
+      return Err("Requalification grace period cannot be negative".to_string());
+    }
+    if self.point_expiry_years < 0 {
+      return Err("Point expiry years cannot be negative".to_string());
+    }
+    if self.reservation_timeout_minutes <= 0 {
+      return Err("Reservation timeout must be positive".to_string());
+    }
+    if self.pending_days < 0 {
+      return Err("Pending days cannot be negative".to_string());
+    }
+    if self.adjustment_approval.threshold < 0 {
+      return Err("Adjustment approval threshold cannot be negative".to_string());
+    }
+    if self.adjustment_approval.timeout_hours <= 0 {
+      return Err("Adjustment request timeout must be positive".to_string());
+    }
+    if self.transfer.daily_limit < 0 || self.transfer.yearly_limit < 0 {
+      return Err("Transfer limits cannot be negative".to_string());
+    }
+    for (i, range) in self.card_ranges.iter().enumerate() {
+      if range.prefix.is_empty() || !range.prefix.chars().all(|c| c.is_ascii_digit()) {
+        return Err(format!("Invalid card range prefix: {}", range.prefix));
+      }
+      if range.serial_digits == 0 || range.number_length() > 19 {
+        return Err(format!(
+          "Card numbers of range {} must be at most 19 digits long",
+          range.prefix
+        ));
+      }
+      if range.first_serial > range.last_serial
+        || range.last_serial >= 10u64.pow(range.serial_digits)
+      {
+        return Err(format!("Invalid serials of card range {}", range.prefix));
+      }
+      if self.card_ranges[..i].iter().any(|r| r.overlaps(range)) {
+        return Err(format!("Overlapping card range: {}", range.prefix));
+      }
+    }
+    if self.birthday.window_days <= 0 {
+      return Err("Birthday window must be positive".to_string());
+    }
+    match self.birthday.reward {
+      Some(BirthdayReward::Bonus { points }) if points < 0 => {
+        return Err("Birthday bonus cannot be negative".to_string())
+      }
+      Some(BirthdayReward::Multiplier { percentage }) if percentage < 100 => {
+        return Err("Birthday multiplier cannot be below 100%".to_string())
+      }
+      _ => (),
+    }
+    Ok(self)
+  }
+
+  /// Expiry moment of points earned at the given time
+  pub fn point_expiry_of(&self, earned_at: DateTime<Utc>) -> DateTime<Utc> {
+    Utc
+      .from_utc_date(&NaiveDate::from_ymd(
+        earned_at.year() + self.point_expiry_years + 1,
+        1,
+        1,
+      ))
+      .and_hms(0, 0, 0)
+  }
+
+  pub fn reservation_timeout(&self) -> Duration {
+    Duration::minutes(self.reservation_timeout_minutes)
+  }
+
+  pub fn pending_period(&self) -> Duration {
+    Duration::days(self.pending_days)
+  }
+
+  pub fn get_tier(&self, level: &LoyaltyLevel) -> Option<&Tier> {
+    self.tiers.iter().find(|t| t.id == level.as_str())
+  }
+
+  /// Position of the given level in the tier table; higher is better
+  pub fn rank(&self, level: &LoyaltyLevel) -> Option<usize> {
+    self.tiers.iter().position(|t| t.id == level.as_str())
+  }
+
+  /// Lowest tier, new accounts start here
+  pub fn base_level(&self) -> LoyaltyLevel {
+    LoyaltyLevel::new(&self.tiers[0].id)
+  }
+
+  /// Highest tier the given yearly turnover qualifies for
+  pub fn level_for_turnover(&self, turnover: i32) -> LoyaltyLevel {
+    self
+      .tiers
+      .iter()
+      .rev()
+      .find(|t| turnover >= t.qualifying_turnover)
+      .map(|t| LoyaltyLevel::new(&t.id))
+      .unwrap_or_else(|| self.base_level())
+  }
+}
@@ -0,0 +1,256 @@
+use crate::amount::{Money, Points};
+use crate::config::LoyaltyConfig;
+use crate::loyalty::{self, Account, AccountExt, AccountStatus, Transaction, TransactionKind};
+use chrono::Utc;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Shared points pool of the household,
+/// the sum of the members' balances
+pub fn get_household_balance<'a, I>(members: I) -> i32
+where
+  I: Iterator<Item = &'a Account>,
+{
+  members.fold(0, |acc, m| acc.saturating_add(m.get_balance()))
+}
+
+/// Combined gross turnover of the household in the given year
+pub fn get_household_turnover<'a, I>(members: I, year: i32) -> i32
+where
+  I: Iterator<Item = &'a Account>,
+{
+  members.fold(0, |acc, m| {
+    acc.saturating_add(m.get_gross_turnover_of_year(year))
+  })
+}
+
+/// Share each member's turnover with the others,
+/// and check their tier against the combined turnover
+/// Members are the accounts of the same household
+pub fn sync_household(members: &mut [Account], config: &LoyaltyConfig) -> Result<(), String> {
+  for i in 0..members.len() {
+    let mut others: BTreeMap<i32, Money> = BTreeMap::new();
+    for other in members
+      .iter()
+      .filter(|m| m.account_id != members[i].account_id)
+    {
+      for (year, turnover) in other.gross_turnover_by_year.iter() {
+        let total = others.entry(*year).or_insert_with(Money::zero);
+        *total = total.checked_add(*turnover)?;
+      }
+    }
+    members[i].household_turnover_by_year = others;
+    members[i].check_loyalty_level(config);
+  }
+  Ok(())
+}
+
+/// Burn points of a household member,
+/// taking what the member lacks from the other members
+/// Either all the accounts change or none of them
+pub fn burn_from_pool(
+  member: &mut Account,
+  others: &mut [Account],
+  purchase_id: Uuid,
+  points: Points,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<Transaction, String> {
+  let mut burner = member.clone();
+  let mut pool = others.to_vec();
+  // Repeated or conflicting burns are decided by the member's own burn
+  if burner.get_burned_points(purchase_id) == 0 {
+    let now = Utc::now();
+    burner.expire_points(now)?;
+    let mut shortfall = points.value() - std::cmp::max(burner.get_spendable_balance(), 0);
+    // Suspended and closed members do not share their points
+    for other in pool
+      .iter_mut()
+      .filter(|m| m.status == AccountStatus::Active)
+    {
+      if shortfall <= 0 {
+        break;
+      }
+      other.expire_points(now)?;
+      let available = std::cmp::min(std::cmp::max(other.get_spendable_balance(), 0), shortfall);
+      if available > 0 {
+        loyalty::pool_points(
+          other,
+          &mut burner,
+          purchase_id,
+          Points::new(available)?,
+          config,
+          created_by,
+        )?;
+        shortfall -= available;
+      }
+    }
+  }
+  // Fails if the whole household has not enough points
+  let burn = burner.burn_points(purchase_id, points, created_by)?;
+  *member = burner;
+  others.clone_from_slice(&pool);
+  Ok(burn)
+}
+
+/// Cancel the burns of a purchase of a household member,
+/// giving the points pooled for them back to the members they came from
+/// Points go back from the oldest lots of the member;
+/// members who left or cannot receive points anymore leave them with the member
+/// Either all the accounts change or none of them
+pub fn cancel_pooled_burn(
+  member: &mut Account,
+  others: &mut [Account],
+  purchase_id: Uuid,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<Transaction, String> {
+  let mut burner = member.clone();
+  let mut pool = others.to_vec();
+  let cancel = burner.cancel_burn(purchase_id, created_by)?;
+  // Points pooled for the purchase and not given back yet, by member
+  let mut pooled: BTreeMap<Uuid, i32> = BTreeMap::new();
+  for transaction in burner
+    .transactions
+    .iter()
+    .filter(|t| t.purchase_id == purchase_id)
+  {
+    match transaction.transaction_kind {
+      TransactionKind::TransferIn {
+        from_account_id, ..
+      } => *pooled.entry(from_account_id).or_insert(0) += transaction.amount.value(),
+      TransactionKind::TransferOut {
+        to_account_id,
+        pooled: true,
+        ..
+      } => *pooled.entry(to_account_id).or_insert(0) -= transaction.amount.value(),
+      _ => (),
+    }
+  }
+  let now = Utc::now();
+  burner.expire_points(now)?;
+  for other in pool.iter_mut().filter(|m| {
+    m.status == AccountStatus::Active && pooled.get(&m.account_id).cloned().unwrap_or(0) > 0
+  }) {
+    // Points lapsed since then are not given back
+    let points = std::cmp::min(
+      pooled[&other.account_id],
+      std::cmp::max(burner.get_spendable_balance(), 0),
+    );
+    if points > 0 {
+      loyalty::pool_points(
+        &mut burner,
+        other,
+        purchase_id,
+        Points::new(points)?,
+        config,
+        created_by,
+      )?;
+    }
+  }
+  *member = burner;
+  others.clone_from_slice(&pool);
+  Ok(cancel)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::amount::Money;
+  use crate::loyalty::PurchaseInfo;
+  use chrono::Datelike;
+  #[test]
+  fn test_household() {
+    let config = LoyaltyConfig::default();
+    let household_id = Uuid::new_v4();
+    let mut members = (0..2)
+      .map(|customer_id| {
+        let mut account = Account::new(
+          customer_id,
+          Utc::today().naive_local(),
+          config.base_level(),
+          0,
+        );
+        account.join_household(household_id).unwrap();
+        account
+      })
+      .collect::<Vec<Account>>();
+    for member in members.iter_mut() {
+      member
+        .close_purchase(
+          PurchaseInfo {
+            purchase_id: Uuid::new_v4(),
+            payable_total_gross: Money::new(30_000).unwrap(),
+            created_by: 0,
+          },
+          &config,
+          &[],
+          0,
+        )
+        .unwrap();
+    }
+    // Neither reaches L2 alone, together they do
+    assert_eq!(members[0].loyalty_level, config.base_level());
+    sync_household(&mut members, &config).unwrap();
+    assert!(members.iter().all(|m| m.loyalty_level.as_str() == "L2"));
+    assert_eq!(
+      get_household_turnover(members.iter(), Utc::today().year()),
+      60_000
+    );
+    assert_eq!(get_household_balance(members.iter()), 1200);
+    // Burn more than the member's own balance from the pool
+    let (member, others) = members.split_first_mut().unwrap();
+    burn_from_pool(
+      member,
+      others,
+      Uuid::new_v4(),
+      Points::new(1000).unwrap(),
+      &config,
+      0,
+    )
+    .unwrap();
+    assert_eq!(member.get_balance(), 0);
+    assert_eq!(others[0].get_balance(), 200);
+    // Not enough points in the household, nothing changes
+    assert!(burn_from_pool(
+      member,
+      others,
+      Uuid::new_v4(),
+      Points::new(201).unwrap(),
+      &config,
+      0
+    )
+    .is_err());
+    assert_eq!(others[0].get_balance(), 200);
+    // Cancelled burn gives the pooled points back to their member
+    let purchase_id = Uuid::new_v4();
+    burn_from_pool(
+      member,
+      others,
+      purchase_id,
+      Points::new(150).unwrap(),
+      &config,
+      0,
+    )
+    .unwrap();
+    assert_eq!(others[0].get_balance(), 50);
+    cancel_pooled_burn(member, others, purchase_id, &config, 0).unwrap();
+    assert_eq!(member.get_balance(), 0);
+    assert_eq!(others[0].get_balance(), 200);
+    // Burned again after the cancel, pooled again
+    burn_from_pool(
+      member,
+      others,
+      purchase_id,
+      Points::new(100).unwrap(),
+      &config,
+      0,
+    )
+    .unwrap();
+    assert_eq!(others[0].get_balance(), 100);
+    cancel_pooled_burn(member, others, purchase_id, &config, 0).unwrap();
+    assert_eq!(others[0].get_balance(), 200);
+    // Nothing left to cancel
+    assert!(cancel_pooled_burn(member, others, purchase_id, &config, 0).is_err());
+  }
+}
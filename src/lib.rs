@@ -1,2 +1,7 @@
+pub mod amount;
+pub mod campaign;
+pub mod card;
+pub mod config;
+pub mod household;
 pub mod loyalty;
 pub mod prelude;
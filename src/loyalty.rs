@@ -1,398 +1,4014 @@
-use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use crate::amount::{Money, Points, Rate, RoundingPolicy};
+use crate::campaign::{self, Campaign};
+use crate::config::{BirthdayReward, ClosingPolicy, LoyaltyConfig};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
 use gzlib::id::LuhnCheck;
 use packman::VecPackMember;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use uuid::Uuid;
 
-const TARGET_TO_JUMP: i32 = 50_000;
+// Layout of the stored accounts, raised when stored accounts need an upgrade
+// 1: per-year turnover
+// 2: point lots
+pub const ACCOUNT_VERSION: u32 = 2;
 
 pub trait AccountExt
 where
   Self: Sized,
 {
-  fn new(customer_id: u32, customer_birthdate: NaiveDate, created_by: u32) -> Self;
+  fn new(
+    customer_id: u32,
+    customer_birthdate: NaiveDate,
+    loyalty_level: LoyaltyLevel,
+    created_by: u32,
+  ) -> Self;
+  fn new_anonymous(loyalty_level: LoyaltyLevel, created_by: u32) -> Self;
+  fn is_anonymous(&self) -> bool;
+  fn link_customer(&mut self, customer_id: u32, birthdate: NaiveDate) -> Result<&Self, String>;
+  fn issue_card(&mut self, card_id: String) -> Result<&Self, String>;
   fn set_card(&mut self, card_id: String) -> Result<&Self, String>;
+  fn change_card_state(
+    &mut self,
+    card_id: &str,
+    state: CardState,
+    reason: String,
+  ) -> Result<&Self, String>;
+  fn replace_card(
+    &mut self,
+    card_id: &str,
+    new_card_id: String,
+    reason: String,
+  ) -> Result<&Self, String>;
+  fn get_card(&self, card_id: &str) -> Option<&Card>;
+  fn get_active_card(&self) -> Option<&Card>;
   fn set_loyalty_level(&mut self, loyalty_level: LoyaltyLevel) -> &Self;
   fn set_birthdate(&mut self, birthdate: NaiveDate) -> &Self;
+  fn join_household(&mut self, household_id: Uuid) -> Result<&Self, String>;
+  fn leave_household(&mut self) -> Result<&Self, String>;
+  fn erase_personal_data(&mut self, erased_by: u32) -> Result<Vec<String>, String>;
+  fn export_personal_data(&self, merged_accounts: &[Account]) -> Result<String, String>;
+  fn set_status(
+    &mut self,
+    status: AccountStatus,
+    reason: String,
+    config: &LoyaltyConfig,
+    changed_by: u32,
+  ) -> Result<Option<Transaction>, String>;
   fn burn_points(
     &mut self,
     purchase_id: Uuid,
-    points_to_burn: i32,
+    points_to_burn: Points,
     created_by: u32,
   ) -> Result<Transaction, String>;
+  fn cancel_burn(&mut self, purchase_id: Uuid, created_by: u32) -> Result<Transaction, String>;
+  fn reserve_points(
+    &mut self,
+    purchase_id: Uuid,
+    points_to_reserve: Points,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<Reservation, String>;
+  fn release_reservation(&mut self, purchase_id: Uuid) -> Result<Points, String>;
+  fn get_reserved_points(&self, now: DateTime<Utc>) -> i32;
+  fn get_spendable_balance(&self) -> i32;
+  fn get_pending_balance(&self, now: DateTime<Utc>) -> i32;
+  fn get_available_balance(&self, now: DateTime<Utc>) -> i32;
+  fn has_expired_reservations(&self, now: DateTime<Utc>) -> bool;
+  fn release_expired_reservations(&mut self, now: DateTime<Utc>) -> Vec<Reservation>;
   fn close_purchase(
     &mut self,
     purchase_info: PurchaseInfo,
+    config: &LoyaltyConfig,
+    campaigns: &[Campaign],
     created_by: u32,
   ) -> Result<PurchaseSummary, String>;
+  fn refund_purchase(
+    &mut self,
+    purchase_id: Uuid,
+    refund_amount: Money,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<Transaction, String>;
+  fn adjust_points(
+    &mut self,
+    amount: i32,
+    reason: AdjustmentReason,
+    notes: String,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<Transaction, String>;
+  fn request_adjustment(
+    &mut self,
+    amount: i32,
+    reason: AdjustmentReason,
+    notes: String,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<AdjustmentOutcome, String>;
+  fn approve_adjustment(
+    &mut self,
+    request_id: Uuid,
+    approved_by: u32,
+    config: &LoyaltyConfig,
+  ) -> Result<Transaction, String>;
+  fn reject_adjustment(
+    &mut self,
+    request_id: Uuid,
+    rejected_by: u32,
+  ) -> Result<AdjustmentRequest, String>;
+  fn has_expired_adjustment_requests(&self, now: DateTime<Utc>) -> bool;
+  fn expire_adjustment_requests(&mut self, now: DateTime<Utc>) -> Vec<AdjustmentRequest>;
   fn get_balance(&self) -> i32;
   fn get_yearly_gross_turnover(&self) -> i32;
-  fn check_loyalty_level(&mut self);
+  fn get_gross_turnover_of_year(&self, year: i32) -> i32;
+  fn get_qualifying_turnover_of_year(&self, year: i32) -> i32;
+  fn check_loyalty_level(&mut self, config: &LoyaltyConfig);
+  fn is_requalification_due(&self, config: &LoyaltyConfig, now: DateTime<Utc>) -> bool;
+  fn requalify(&mut self, config: &LoyaltyConfig, now: DateTime<Utc>);
   fn get_burned_points(&mut self, purchase_id: Uuid) -> i32;
+  fn needs_upgrade(&self) -> bool;
+  fn upgrade(&mut self, config: &LoyaltyConfig, now: DateTime<Utc>) -> Result<(), String>;
+  fn has_expired_points(&self, now: DateTime<Utc>) -> bool;
+  fn expire_points(&mut self, now: DateTime<Utc>) -> Result<Vec<Transaction>, String>;
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Account {
   pub account_id: Uuid, // Account ID cannot be changed later
-  pub customer_id: u32, // customer ID
-  pub customer_birthdate: NaiveDate,
-  pub card_id: Option<String>,
+  // Layout the account was stored with, 0 if stored before versioning
+  #[serde(default)]
+  pub version: u32,
+  pub customer_id: Option<u32>, // customer ID; None for anonymous card accounts
+  pub customer_birthdate: Option<NaiveDate>,
+  #[serde(default)]
+  pub cards: Vec<Card>, // Every card ever issued to the account
+  // Single card ID stored before multiple cards,
+  // moved into cards on upgrade
+  #[serde(rename = "card_id", default, skip_serializing)]
+  legacy_card_id: Option<String>,
   pub loyalty_level: LoyaltyLevel,
   pub balance_points: i32,
-  pub yearly_gross_turnover: i32,
+  #[serde(default)]
+  pub gross_turnover_by_year: BTreeMap<i32, Money>, // Year => gross turnover
   pub transactions: Vec<Transaction>,
+  #[serde(default)]
+  pub level_changes: Vec<LevelChange>,
+  // Year of the last yearly requalification
+  #[serde(default = "requalified_this_year")]
+  pub last_requalification: Option<i32>,
+  #[serde(default)]
+  pub pending_downgrade: Option<PendingDowngrade>,
+  #[serde(default)]
+  pub point_lots: Vec<PointLot>, // Earned points with expiry, oldest first
+  #[serde(default)]
+  pub reservations: Vec<Reservation>, // Points on hold for open purchases
+  #[serde(default)]
+  pub closed_purchases: Vec<ClosedPurchase>,
+  #[serde(default)]
+  pub adjustment_requests: Vec<AdjustmentRequest>, // Large adjustments waiting for approval
+  #[serde(default)]
+  pub household_id: Option<Uuid>,
+  // Year => gross turnover of the other household members
+  // Kept in sync by the service, counts toward the tier
+  #[serde(default)]
+  pub household_turnover_by_year: BTreeMap<i32, Money>,
+  // Set when the account was merged into another one;
+  // kept only to redirect lookups
+  #[serde(default)]
+  pub merged_into: Option<Uuid>,
+  #[serde(default)]
+  pub merges: Vec<AccountMerge>, // Merges the account took part in
+  #[serde(default)]
+  pub status: AccountStatus,
+  #[serde(default)]
+  pub status_changes: Vec<StatusChange>,
+  // Set when the personal data was erased on request;
+  // the anonymized account is kept for accounting only
+  #[serde(default)]
+  pub erased_by: Option<u32>,
+  #[serde(default)]
+  pub erased_at: Option<DateTime<Utc>>,
   pub created_by: u32,
   pub created_at: DateTime<Utc>,
 }
 
-impl AccountExt for Account {
-  fn new(customer_id: u32, customer_birthdate: NaiveDate, created_by: u32) -> Self {
-    Self {
-      account_id: Uuid::new_v4(),
-      customer_id,
-      customer_birthdate,
-      card_id: None,
-      loyalty_level: LoyaltyLevel::L1,
-      balance_points: 0,
-      yearly_gross_turnover: 0,
-      transactions: Vec::new(),
-      created_by,
-      created_at: Utc::now(),
+impl Account {
+  // Merged accounts cannot be used anymore
+  fn check_usable(&self) -> Result<(), String> {
+    if let Some(merged_into) = self.merged_into {
+      return Err(format!(
+        "A fiók be lett olvasztva a(z) {} fiókba!",
+        merged_into
+      ));
     }
+    if self.erased_at.is_some() {
+      return Err("A fiók személyes adatai törölve lettek, a fiók nem használható!".to_string());
+    }
+    Ok(())
   }
 
-  fn set_card(&mut self, card_id: String) -> Result<&Self, String> {
-    // Check if Card ID is valid
-    let card_id = card_id
-      .luhn_check()
-      .map_err(|_| "A megadott kártya azonosító nem valid!".to_string())?;
-    // Set new card id
-    self.card_id = Some(card_id);
-    //Return Ok self ref
-    Ok(self)
+  // Points can be collected and burned on active accounts only
+  fn check_active(&self) -> Result<(), String> {
+    match self.status {
+      AccountStatus::Active => Ok(()),
+      AccountStatus::Suspended => Err("A fiók fel van függesztve!".to_string()),
+      AccountStatus::Closed => Err("A fiók le van zárva!".to_string()),
+    }
   }
 
-  fn set_loyalty_level(&mut self, loyalty_level: LoyaltyLevel) -> &Self {
+  // Set new loyalty level and record the change with its reason
+  fn change_loyalty_level(&mut self, loyalty_level: LoyaltyLevel, reason: LevelChangeReason) {
+    if self.loyalty_level == loyalty_level {
+      return;
+    }
+    self.level_changes.push(LevelChange {
+      from: self.loyalty_level.clone(),
+      to: loyalty_level.clone(),
+      reason,
+      created_at: Utc::now(),
+    });
     self.loyalty_level = loyalty_level;
-    self
   }
 
-  fn set_birthdate(&mut self, birthdate: NaiveDate) -> &Self {
-    self.customer_birthdate = birthdate;
-    self
-  }
-
-  fn burn_points(
+  // Write a burn transaction without any balance check
+  fn push_burn(
     &mut self,
     purchase_id: Uuid,
-    points_to_burn: i32,
+    points_to_burn: Points,
     created_by: u32,
   ) -> Result<Transaction, String> {
-    if self.get_balance() < points_to_burn {
-      return Err(format!(
-        "Nincs elég pont a tranzakcióhoz. Jelenlegi pont: {}",
-        self.get_balance()
-      ));
-    }
+    // Check balance overflow first
+    let balance = points_to_burn.debit(self.balance_points)?;
 
     // Create new transaction object
     let transaction = Transaction::new(
       purchase_id,
-      self.account_id.clone(),
+      self.account_id,
       TransactionKind::Burn,
       points_to_burn,
       created_by,
     );
 
     // Update balance
-    self.balance_points -= points_to_burn;
+    self.balance_points = balance;
+
+    // Take burned points from the oldest lots
+    self.consume_lots(
+      transaction.transaction_id,
+      points_to_burn,
+      None,
+      LotSource::Available,
+    )?;
 
     // Push new transaction to transactions
     self.transactions.push(transaction.clone());
 
-    // Return Ok transaction
     Ok(transaction)
   }
 
-  fn close_purchase(
+  fn is_purchase_closed(&self, purchase_id: Uuid) -> bool {
+    self.transactions.iter().any(|t| {
+      t.purchase_id == purchase_id && matches!(t.transaction_kind, TransactionKind::Earn { .. })
+    })
+  }
+
+  // IDs of the burn transactions of the given purchase
+  // that are not cancelled
+  fn get_active_burn_ids(&self, purchase_id: Uuid) -> Vec<Uuid> {
+    let cancelled = self
+      .transactions
+      .iter()
+      .filter(|t| t.purchase_id == purchase_id)
+      .filter_map(|t| match &t.transaction_kind {
+        TransactionKind::BurnCancel {
+          burn_transaction_ids,
+        } => Some(burn_transaction_ids.clone()),
+        _ => None,
+      })
+      .flatten()
+      .collect::<Vec<Uuid>>();
+    self
+      .transactions
+      .iter()
+      .filter(|t| t.purchase_id == purchase_id)
+      .filter(|t| match t.transaction_kind {
+        TransactionKind::Burn => !cancelled.contains(&t.transaction_id),
+        _ => false,
+      })
+      .map(|t| t.transaction_id)
+      .collect()
+  }
+
+  // Downgrade if the tier was reached by turnover that got refunded
+  // Tiers set by staff or by requalification are kept
+  fn check_loyalty_level_after_refund(&mut self, purchase_id: Uuid, config: &LoyaltyConfig) {
+    match self.level_changes.last() {
+      Some(LevelChange {
+        reason: LevelChangeReason::Turnover { .. },
+        ..
+      }) => (),
+      _ => return,
+    }
+    let year = Utc::today().year();
+    // Still eligible for the tier reached by this or last year's turnover
+    let eligible = std::cmp::max_by_key(
+      config.level_for_turnover(self.get_qualifying_turnover_of_year(year - 1)),
+      config.level_for_turnover(self.get_qualifying_turnover_of_year(year)),
+      |level| config.rank(level),
+    );
+    if config.rank(&eligible) < config.rank(&self.loyalty_level) {
+      self.change_loyalty_level(eligible, LevelChangeReason::Refund { purchase_id });
+    }
+  }
+
+  // Birthday reward for a purchase closed at the given time
+  // with the given earned points, with the year it is given for
+  // Given at most once a year; a reward taken back in full
+  // by refunds is given again on a later purchase
+  fn get_birthday_reward(
+    &self,
+    points_earned: Points,
+    config: &LoyaltyConfig,
+    now: DateTime<Utc>,
+  ) -> Result<Option<(i32, Points)>, String> {
+    let reward = match &config.birthday.reward {
+      Some(reward) => reward,
+      None => return Ok(None),
+    };
+    // Anonymous accounts have no birthday
+    let birthdate = match self.customer_birthdate {
+      Some(birthdate) => birthdate,
+      None => return Ok(None),
+    };
+    let year = match config
+      .birthday
+      .celebrated_year(birthdate, now.naive_utc().date())
+    {
+      Some(year) => year,
+      None => return Ok(None),
+    };
+    for transaction in self.transactions.iter() {
+      if matches!(transaction.transaction_kind, TransactionKind::Birthday { year: y } if y == year)
+        && !self.kept_birthday_points(transaction)?.is_zero()
+      {
+        return Ok(None);
+      }
+    }
+    let points = match reward {
+      BirthdayReward::Bonus { points } => Points::new(*points)?,
+      // Extra points on top of the earned ones
+      BirthdayReward::Multiplier { percentage } => config
+        .rounding
+        .percentage(points_earned, percentage.saturating_sub(100))?,
+    };
+    // Nothing to give, keep the reward for a later purchase
+    if points.is_zero() {
+      return Ok(None);
+    }
+    Ok(Some((year, points)))
+  }
+
+  // Points of a birthday reward not taken back by refunds
+  fn kept_birthday_points(&self, birthday: &Transaction) -> Result<Points, String> {
+    let reversed =
+      Points::checked_sum(
+        self
+          .transactions
+          .iter()
+          .filter_map(|t| match t.transaction_kind {
+            TransactionKind::BirthdayRefund {
+              birthday_transaction_id,
+            } if birthday_transaction_id == birthday.transaction_id => Some(t.amount),
+            _ => None,
+          }),
+      )?;
+    birthday.amount.checked_sub(reversed)
+  }
+
+  // Store an earning transaction with its points as a new lot
+  fn push_earning(
     &mut self,
-    purchase_info: PurchaseInfo,
-    created_by: u32,
-  ) -> Result<PurchaseSummary, String> {
-    // Check if we should upgrade loyalty level
-    self.check_loyalty_level();
+    transaction: Transaction,
+    config: &LoyaltyConfig,
+  ) -> Result<(), String> {
+    let balance = transaction.amount.credit(self.balance_points)?;
+    // Points of a purchase are held until it cannot be returned
+    let available_at = match transaction.transaction_kind {
+      TransactionKind::Earn { .. } | TransactionKind::Birthday { .. } => {
+        transaction.created_at + config.pending_period()
+      }
+      _ => transaction.created_at,
+    };
+    // Earned points form a new lot with its own expiry
+    if !transaction.amount.is_zero() {
+      self.point_lots.push(PointLot {
+        lot_id: transaction.transaction_id,
+        amount: transaction.amount,
+        usages: Vec::new(),
+        created_at: transaction.created_at,
+        available_at,
+        expires_at: config.point_expiry_of(transaction.created_at),
+      });
+    }
+    self.transactions.push(transaction);
+    self.balance_points = balance;
+    Ok(())
+  }
 
-    // Calculate points to earn
-    let points_to_earn = (self.loyalty_level.get_discount_percentage()
-      * purchase_info.payable_total_gross as f32)
-      .round() as i32;
+  // Year => gross turnover of the purchases, less their refunds
+  fn rebuild_turnover_by_year(&self) -> Result<BTreeMap<i32, Money>, String> {
+    let mut turnover_by_year: BTreeMap<i32, Money> = BTreeMap::new();
+    for transaction in self.transactions.iter() {
+      if let TransactionKind::Earn {
+        total_payable_amount,
+        ..
+      } = transaction.transaction_kind
+      {
+        let total = turnover_by_year
+          .entry(transaction.created_at.year())
+          .or_insert_with(Money::zero);
+        *total = total.checked_add(total_payable_amount)?;
+      }
+    }
+    for transaction in self.transactions.iter() {
+      if let TransactionKind::Refund {
+        earn_transaction_id,
+        refund_amount,
+        ..
+      } = transaction.transaction_kind
+      {
+        // Refunds count in the year of the purchase
+        let year = match self
+          .transactions
+          .iter()
+          .find(|t| t.transaction_id == earn_transaction_id)
+        {
+          Some(earn) => earn.created_at.year(),
+          None => continue,
+        };
+        if let Some(total) = turnover_by_year.get_mut(&year) {
+          *total = total.checked_sub(refund_amount).unwrap_or_default();
+        }
+      }
+    }
+    Ok(turnover_by_year)
+  }
 
-    // Create transaction
+  // Pending adjustment request the given user can decide on
+  fn get_pending_adjustment_request(
+    &self,
+    request_id: Uuid,
+    decided_by: u32,
+  ) -> Result<AdjustmentRequest, String> {
+    let request = self
+      .adjustment_requests
+      .iter()
+      .find(|r| r.request_id == request_id)
+      .ok_or("A megadott korrekciós kérés nem található!".to_string())?;
+    if request.status != AdjustmentStatus::Pending || request.expires_at <= Utc::now() {
+      return Err("A korrekciós kérés már nem függőben lévő!".to_string());
+    }
+    // Maker and checker must be different users
+    if request.requested_by == decided_by {
+      return Err("A korrekciós kérést csak másik felhasználó bírálhatja el!".to_string());
+    }
+    Ok(request.clone())
+  }
+
+  // Remove the personal data, keep what accounting needs
+  // Returns the removed card IDs
+  fn scrub_personal_data(&mut self, erased_by: u32) -> Vec<String> {
+    // Cards identify the customer, they are removed entirely
+    let card_ids = self
+      .cards
+      .drain(..)
+      .map(|c| c.card_id)
+      .collect::<Vec<String>>();
+    self.customer_id = None;
+    self.customer_birthdate = None;
+    self.household_id = None;
+    self.household_turnover_by_year = BTreeMap::new();
+    // Free text may hold personal data;
+    // amounts, dates and reason codes are kept for accounting
+    for transaction in self.transactions.iter_mut() {
+      if let TransactionKind::Adjustment { notes, .. } = &mut transaction.transaction_kind {
+        notes.clear();
+      }
+    }
+    for request in self.adjustment_requests.iter_mut() {
+      request.notes.clear();
+    }
+    for change in self.status_changes.iter_mut() {
+      change.reason.clear();
+    }
+    for merge in self.merges.iter_mut() {
+      merge.card_ids.clear();
+    }
+    self.erased_by = Some(erased_by);
+    self.erased_at = Some(Utc::now());
+    card_ids
+  }
+
+  // Write an adjustment transaction without any status check
+  fn push_adjustment(
+    &mut self,
+    amount: i32,
+    reason: AdjustmentReason,
+    notes: String,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<Transaction, String> {
+    check_adjustment(amount, reason, &notes)?;
+    let points = Points::new(
+      amount
+        .checked_abs()
+        .ok_or_else(|| format!("Hibás pontszám: {}", amount))?,
+    )?;
+    if amount < 0 {
+      // Removed points are taken from pending lots too,
+      // so the available balance cannot go below zero
+      return self.push_debit_adjustment(
+        points,
+        reason,
+        notes,
+        created_by,
+        LotSource::AvailableFirst,
+      );
+    }
+    // Adjustment is not related to any purchase
     let transaction = Transaction::new(
-      purchase_info.purchase_id,
-      self.account_id.clone(),
-      TransactionKind::Earn {
-        total_payable_amount: purchase_info.payable_total_gross as i32,
-        discount: self.loyalty_level.get_discount_percentage(),
+      Uuid::default(),
+      self.account_id,
+      TransactionKind::Adjustment {
+        reason,
+        notes,
+        is_credit: true,
       },
-      points_to_earn,
+      points,
       created_by,
     );
+    // Added points form a new lot like earned ones
+    self.push_earning(transaction.clone(), config)?;
+    Ok(transaction)
+  }
 
-    // Store transaction to transactions
-    self.transactions.push(transaction);
-
-    // Update balance
-    self.balance_points += points_to_earn;
-
-    // Update yearly turnover
-    self.yearly_gross_turnover += purchase_info.payable_total_gross as i32;
+  // Write an adjustment removing points from the given lots
+  // Staff correction may take the balance below zero
+  fn push_debit_adjustment(
+    &mut self,
+    points: Points,
+    reason: AdjustmentReason,
+    notes: String,
+    created_by: u32,
+    source: LotSource,
+  ) -> Result<Transaction, String> {
+    let balance = points.debit(self.balance_points)?;
+    let transaction = Transaction::new(
+      Uuid::default(),
+      self.account_id,
+      TransactionKind::Adjustment {
+        reason,
+        notes,
+        is_credit: false,
+      },
+      points,
+      created_by,
+    );
+    self.consume_lots(transaction.transaction_id, points, None, source)?;
+    self.balance_points = balance;
+    self.transactions.push(transaction.clone());
+    Ok(transaction)
+  }
 
-    // Check if we should upgrade loyalty level
-    self.check_loyalty_level();
+  // Apply the adjustment, or keep it for approval above the threshold
+  fn queue_adjustment(
+    &mut self,
+    amount: i32,
+    reason: AdjustmentReason,
+    notes: String,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<AdjustmentOutcome, String> {
+    check_adjustment(amount, reason, &notes)?;
+    // Small adjustments apply straight away
+    if amount.checked_abs().unwrap_or(i32::MAX) <= config.adjustment_approval.threshold {
+      return self
+        .push_adjustment(amount, reason, notes, config, created_by)
+        .map(AdjustmentOutcome::Applied);
+    }
+    let requested_at = Utc::now();
+    let request = AdjustmentRequest {
+      request_id: Uuid::new_v4(),
+      account_id: self.account_id,
+      amount,
+      reason,
+      notes,
+      status: AdjustmentStatus::Pending,
+      requested_by: created_by,
+      requested_at,
+      decided_by: None,
+      decided_at: None,
+      expires_at: requested_at + config.adjustment_approval.timeout(),
+    };
+    self.adjustment_requests.push(request.clone());
+    Ok(AdjustmentOutcome::Pending(request))
+  }
 
-    let burned_points = self.get_burned_points(purchase_info.purchase_id);
-    let earned_points = points_to_earn;
-    let balance_closing = self.get_balance();
-    let balance_opening = balance_closing - earned_points + burned_points;
+  // Record the decision on an adjustment request
+  fn decide_adjustment_request(
+    &mut self,
+    request_id: Uuid,
+    status: AdjustmentStatus,
+    decided_by: u32,
+  ) -> AdjustmentRequest {
+    let request = self
+      .adjustment_requests
+      .iter_mut()
+      .find(|r| r.request_id == request_id)
+      .expect("Adjustment request must exist");
+    request.status = status;
+    request.decided_by = Some(decided_by);
+    request.decided_at = Some(Utc::now());
+    request.clone()
+  }
 
-    Ok(PurchaseSummary {
-      balance_opening,
-      burned_points,
-      earned_points,
-      balance_closing,
-    })
+  // Points transferred out since the given time
+  fn get_transferred_out_since(&self, since: DateTime<Utc>) -> Result<Points, String> {
+    Points::checked_sum(
+      self
+        .transactions
+        .iter()
+        .filter(|t| t.created_at >= since)
+        .filter(|t| {
+          matches!(
+            t.transaction_kind,
+            TransactionKind::TransferOut { pooled: false, .. }
+          )
+        })
+        .map(|t| t.amount),
+    )
   }
 
-  fn get_balance(&self) -> i32 {
-    self.balance_points
+  // Debit side of a transfer
+  // Pending points are moved too if the lot source allows
+  fn transfer_out(
+    &mut self,
+    transfer_id: Uuid,
+    to_account_id: Uuid,
+    points: Points,
+    pooled: bool,
+    source: LotSource,
+    created_by: u32,
+  ) -> Result<Transaction, String> {
+    self.check_usable()?;
+    self.check_active()?;
+    let now = Utc::now();
+    // Lapsed points cannot be transferred
+    self.expire_points(now)?;
+    let transferable = match source {
+      LotSource::Available => self.get_spendable_balance(),
+      _ => self
+        .get_spendable_balance()
+        .saturating_add(self.get_pending_balance(now)),
+    };
+    if transferable < points.value() {
+      return Err(format!(
+        "Nincs elég pont az átvezetéshez. Jelenlegi pont: {}",
+        transferable
+      ));
+    }
+    let balance = points.debit(self.balance_points)?;
+    // Transfer is not related to any purchase
+    let transaction = Transaction::new(
+      Uuid::default(),
+      self.account_id,
+      TransactionKind::TransferOut {
+        transfer_id,
+        to_account_id,
+        pooled,
+      },
+      points,
+      created_by,
+    );
+    self.consume_lots(transaction.transaction_id, points, None, source)?;
+    self.balance_points = balance;
+    self.transactions.push(transaction.clone());
+    Ok(transaction)
   }
 
-  fn check_loyalty_level(&mut self) {
-    match self.loyalty_level {
-      LoyaltyLevel::L1 => {
-        // If yearly total is higher or eq with
-        // the given target
-        if self.get_yearly_gross_turnover() >= TARGET_TO_JUMP {
-          self.loyalty_level = LoyaltyLevel::L2
-        }
-      }
-      LoyaltyLevel::L2 => (), // Do nothing
+  fn check_transfer_limits(
+    &self,
+    points: Points,
+    config: &LoyaltyConfig,
+    now: DateTime<Utc>,
+  ) -> Result<(), String> {
+    let today = now.date().and_hms(0, 0, 0);
+    let transferred_today = self.get_transferred_out_since(today)?.checked_add(points)?;
+    if transferred_today.value() > config.transfer.daily_limit {
+      return Err(format!(
+        "A napi átvezetési limit ({}) túllépve!",
+        config.transfer.daily_limit
+      ));
     }
+    let year_start = Utc.ymd(now.year(), 1, 1).and_hms(0, 0, 0);
+    let transferred_this_year = self
+      .get_transferred_out_since(year_start)?
+      .checked_add(points)?;
+    if transferred_this_year.value() > config.transfer.yearly_limit {
+      return Err(format!(
+        "Az éves átvezetési limit ({}) túllépve!",
+        config.transfer.yearly_limit
+      ));
+    }
+    Ok(())
   }
 
-  fn get_burned_points(&mut self, purchase_id: Uuid) -> i32 {
-    self.transactions.iter().fold(0, |acc, t| {
-      if t.purchase_id == purchase_id {
-        match t.transaction_kind {
-          TransactionKind::Burn => return acc + t.amount,
-          _ => return acc,
-        }
-      }
-      acc
-    })
+  // Credit side of a transfer
+  // Points keep the availability and expiry of the lots
+  // they were taken from (amount, available at, expires at),
+  // so transfers cannot extend their life or make them spendable
+  fn transfer_in(
+    &mut self,
+    transfer_id: Uuid,
+    from_account_id: Uuid,
+    lots: Vec<(Points, DateTime<Utc>, DateTime<Utc>)>,
+    points: Points,
+    created_by: u32,
+  ) -> Result<Transaction, String> {
+    self.check_usable()?;
+    self.check_active()?;
+    let balance = points.credit(self.balance_points)?;
+    let transaction = Transaction::new(
+      Uuid::default(),
+      self.account_id,
+      TransactionKind::TransferIn {
+        transfer_id,
+        from_account_id,
+      },
+      points,
+      created_by,
+    );
+    for (i, (amount, available_at, expires_at)) in lots.into_iter().enumerate() {
+      self.point_lots.push(PointLot {
+        // First lot is identified by the transaction itself
+        lot_id: match i {
+          0 => transaction.transaction_id,
+          _ => Uuid::new_v4(),
+        },
+        amount,
+        usages: Vec::new(),
+        created_at: transaction.created_at,
+        available_at,
+        expires_at,
+      });
+    }
+    // Keep lots in expiry order, so the soonest expiring is burned first
+    self.point_lots.sort_by_key(|lot| lot.expires_at);
+    self.balance_points = balance;
+    self.transactions.push(transaction.clone());
+    Ok(transaction)
   }
 
-  fn get_yearly_gross_turnover(&self) -> i32 {
-    self
-      .transactions
-      .iter()
-      // Filter current year transactions
-      .filter(|tr| tr.created_at.year() == Utc::today().naive_local().year())
-      // Fold only Earns
-      .fold(0, |acc, tr| {
-        acc
-          + match tr.transaction_kind {
-            TransactionKind::Earn {
-              total_payable_amount,
-              discount: _,
-            } => total_payable_amount,
-            TransactionKind::Burn => 0,
+  // Take points from the lots, the preferred one first if any,
+  // then the oldest of the given source first
+  // Pending lots are also taken if preferred (refund of their purchase)
+  // Balance not covered by lots (points earned before lots existed)
+  // is simply not tracked here
+  fn consume_lots(
+    &mut self,
+    transaction_id: Uuid,
+    points: Points,
+    preferred_lot: Option<Uuid>,
+    source: LotSource,
+  ) -> Result<(), String> {
+    let mut left = points;
+    let now = Utc::now();
+    // Stable sort keeps the oldest first order after the preferred lot,
+    // with available lots before pending ones
+    let mut order = (0..self.point_lots.len())
+      .filter(|i| {
+        let lot = &self.point_lots[*i];
+        let pending = lot.available_at > now;
+        Some(lot.lot_id) == preferred_lot
+          || match source {
+            LotSource::Available => !pending,
+            LotSource::AvailableFirst => true,
+            LotSource::Pending => pending,
           }
       })
+      .collect::<Vec<usize>>();
+    order.sort_by_key(|i| {
+      let lot = &self.point_lots[*i];
+      (Some(lot.lot_id) != preferred_lot, lot.available_at > now)
+    });
+    for i in order {
+      if left.is_zero() {
+        break;
+      }
+      let lot = &mut self.point_lots[i];
+      let amount = std::cmp::min(lot.remaining(), left);
+      if !amount.is_zero() {
+        lot.usages.push(LotUsage {
+          transaction_id,
+          amount,
+        });
+        left = left.checked_sub(amount)?;
+      }
+    }
+    Ok(())
   }
 }
 
-impl Default for Account {
-  fn default() -> Self {
+impl AccountExt for Account {
+  fn new(
+    customer_id: u32,
+    customer_birthdate: NaiveDate,
+    loyalty_level: LoyaltyLevel,
+    created_by: u32,
+  ) -> Self {
     Self {
-      account_id: Uuid::default(),
-      customer_id: 0,
-      customer_birthdate: Utc::today().naive_utc(),
-      card_id: None,
-      loyalty_level: LoyaltyLevel::default(),
+      account_id: Uuid::new_v4(),
+      version: ACCOUNT_VERSION,
+      customer_id: Some(customer_id),
+      customer_birthdate: Some(customer_birthdate),
+      cards: Vec::new(),
+      legacy_card_id: None,
+      loyalty_level,
       balance_points: 0,
-      yearly_gross_turnover: 0, // ok now its total; NOT yearly
+      gross_turnover_by_year: BTreeMap::new(),
       transactions: Vec::new(),
-      created_by: 0,
+      level_changes: Vec::new(),
+      // Nothing to requalify for until next year
+      last_requalification: requalified_this_year(),
+      pending_downgrade: None,
+      point_lots: Vec::new(),
+      reservations: Vec::new(),
+      closed_purchases: Vec::new(),
+      adjustment_requests: Vec::new(),
+      household_id: None,
+      household_turnover_by_year: BTreeMap::new(),
+      merged_into: None,
+      merges: Vec::new(),
+      status: AccountStatus::Active,
+      status_changes: Vec::new(),
+      erased_by: None,
+      erased_at: None,
+      created_by,
       created_at: Utc::now(),
     }
   }
-}
-
-impl VecPackMember for Account {
-  type Out = Uuid;
 
-  fn get_id(&self) -> &Self::Out {
-    &self.account_id
+  fn new_anonymous(loyalty_level: LoyaltyLevel, created_by: u32) -> Self {
+    Self {
+      account_id: Uuid::new_v4(),
+      loyalty_level,
+      created_by,
+      created_at: Utc::now(),
+      ..Default::default()
+    }
   }
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub enum LoyaltyLevel {
-  L1, // 2%
-  L2, // 4%
-}
 
-impl Default for LoyaltyLevel {
-  fn default() -> Self {
-    Self::L1
+  fn is_anonymous(&self) -> bool {
+    self.customer_id.is_none()
   }
-}
 
-impl LoyaltyLevel {
-  pub fn get_discount_percentage(&self) -> f32 {
-    match self {
-      LoyaltyLevel::L1 => 0.02,
-      LoyaltyLevel::L2 => 0.04,
+  fn link_customer(&mut self, customer_id: u32, birthdate: NaiveDate) -> Result<&Self, String> {
+    self.check_usable()?;
+    if !self.is_anonymous() {
+      return Err("A fiók már vásárlóhoz tartozik!".to_string());
     }
+    self.customer_id = Some(customer_id);
+    self.customer_birthdate = Some(birthdate);
+    Ok(self)
   }
-  pub fn from_str(str: &str) -> Result<Self, String> {
-    match str {
-      "l1" | "L1" => Ok(Self::L1),
-      "l2" | "L2" => Ok(Self::L2),
-      _ => Err("Nem megfelelő kedvezmény színt! L1, vagy L2".to_string()),
+
+  fn issue_card(&mut self, card_id: String) -> Result<&Self, String> {
+    self.check_usable()?;
+    // Check if Card ID is valid
+    let card_id = normalize_card_id(&card_id)?;
+    if self.get_card(&card_id).is_some() {
+      return Err("A megadott kártya már a fiókhoz tartozik!".to_string());
     }
+    self.cards.push(Card {
+      card_id,
+      state: CardState::Issued,
+      reason: "".to_string(),
+      issued_at: Utc::now(),
+      retired_at: None,
+    });
+    Ok(self)
   }
-}
 
-impl ToString for LoyaltyLevel {
-  fn to_string(&self) -> String {
-    match self {
-      LoyaltyLevel::L1 => format!("L1"),
-      LoyaltyLevel::L2 => format!("L2"),
+  fn set_card(&mut self, card_id: String) -> Result<&Self, String> {
+    // Issue the card and make it usable right away
+    self.issue_card(card_id)?;
+    let (card, previous) = self.cards.split_last_mut().expect("Card must exist");
+    // The new card takes over from the one in use,
+    // and from blocked ones, so they cannot be activated again beside it
+    for previous in previous.iter_mut() {
+      if matches!(previous.state, CardState::Active | CardState::Blocked) {
+        previous.change_state(CardState::Replaced, format!("Új kártya: {}", card.card_id))?;
+      }
     }
+    card.state = CardState::Active;
+    //Return Ok self ref
+    Ok(self)
   }
-}
 
-pub struct PurchaseInfo {
-  pub purchase_id: Uuid,
-  pub payable_total_gross: u32,
-  pub created_by: u32,
-}
+  fn change_card_state(
+    &mut self,
+    card_id: &str,
+    state: CardState,
+    reason: String,
+  ) -> Result<&Self, String> {
+    // Replacement needs the new card
+    if state == CardState::Replaced {
+      return Err("Kártyacseréhez meg kell adni az új kártyát!".to_string());
+    }
+    let card = self
+      .cards
+      .iter_mut()
+      .find(|c| c.card_id == card_id)
+      .ok_or("A megadott kártya nem tartozik a fiókhoz!".to_string())?;
+    card.change_state(state, reason)?;
+    Ok(self)
+  }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub enum TransactionKind {
-  Earn {
-    total_payable_amount: i32,
-    discount: f32,
-  },
-  Burn,
-}
+  fn replace_card(
+    &mut self,
+    card_id: &str,
+    new_card_id: String,
+    reason: String,
+  ) -> Result<&Self, String> {
+    // Keep the old card unchanged if the new one is wrong
+    let mut account = self.clone();
+    account
+      .cards
+      .iter_mut()
+      .find(|c| c.card_id == card_id)
+      .ok_or("A megadott kártya nem tartozik a fiókhoz!".to_string())?
+      .change_state(CardState::Replaced, reason)?;
+    account.set_card(new_card_id)?;
+    *self = account;
+    Ok(self)
+  }
 
-impl Default for TransactionKind {
-  fn default() -> Self {
-    Self::Burn
+  fn get_card(&self, card_id: &str) -> Option<&Card> {
+    self.cards.iter().find(|c| c.card_id == card_id)
   }
+
+  fn get_active_card(&self) -> Option<&Card> {
+    // Latest issued first
+    self
+      .cards
+      .iter()
+      .rev()
+      .find(|c| c.state == CardState::Active)
+  }
+
+  fn set_loyalty_level(&mut self, loyalty_level: LoyaltyLevel) -> &Self {
+    // Manual setting overrides any scheduled downgrade
+    self.pending_downgrade = None;
+    self.change_loyalty_level(loyalty_level, LevelChangeReason::Manual);
+    self
+  }
+
+  fn set_birthdate(&mut self, birthdate: NaiveDate) -> &Self {
+    self.customer_birthdate = Some(birthdate);
+    self
+  }
+
+  fn join_household(&mut self, household_id: Uuid) -> Result<&Self, String> {
+    self.check_usable()?;
+    match self.household_id {
+      Some(current) if current == household_id => Ok(self),
+      Some(_) => Err("A fiók már egy másik háztartás tagja!".to_string()),
+      None => {
+        self.household_id = Some(household_id);
+        Ok(self)
+      }
+    }
+  }
+
+  fn set_status(
+    &mut self,
+    status: AccountStatus,
+    reason: String,
+    config: &LoyaltyConfig,
+    changed_by: u32,
+  ) -> Result<Option<Transaction>, String> {
+    self.check_usable()?;
+    if !self.status.can_change_to(status) {
+      return Err(format!(
+        "A fiók nem állítható {} állapotból {} állapotba!",
+        self.status, status
+      ));
+    }
+    if status != AccountStatus::Active && reason.trim().is_empty() {
+      return Err("A felfüggesztés és a lezárás oka kötelező!".to_string());
+    }
+    // Work on a copy, nothing changes if closing fails
+    let mut account = self.clone();
+    let transaction = match status {
+      AccountStatus::Closed => {
+        let now = Utc::now();
+        // Open purchases are abandoned
+        account.reservations.clear();
+        account.expire_points(now)?;
+        // Requests made while open are not decided anymore
+        for request in account.adjustment_requests.iter_mut() {
+          if request.status == AdjustmentStatus::Pending {
+            request.status = AdjustmentStatus::Expired;
+            request.decided_at = Some(now);
+          }
+        }
+        // Pending points are not paid out, they are forfeited
+        let pending = std::cmp::min(account.get_pending_balance(now), account.get_balance());
+        if config.closing_policy == ClosingPolicy::PayOut && pending > 0 {
+          account.push_debit_adjustment(
+            Points::new(pending)?,
+            AdjustmentReason::Forfeit,
+            reason.clone(),
+            changed_by,
+            LotSource::Pending,
+          )?;
+        }
+        match (account.get_balance(), config.closing_policy) {
+          (balance, ClosingPolicy::Forfeit) if balance > 0 => Some(account.push_adjustment(
+            -balance,
+            AdjustmentReason::Forfeit,
+            reason.clone(),
+            config,
+            changed_by,
+          )?),
+          // Large payouts wait for approval like any other adjustment
+          (balance, ClosingPolicy::PayOut) if balance > 0 => match account.queue_adjustment(
+            -balance,
+            AdjustmentReason::PayOut,
+            reason.clone(),
+            config,
+            changed_by,
+          )? {
+            AdjustmentOutcome::Applied(transaction) => Some(transaction),
+            AdjustmentOutcome::Pending(_) => None,
+          },
+          _ => None,
+        }
+      }
+      _ => None,
+    };
+    account.status_changes.push(StatusChange {
+      from: account.status,
+      to: status,
+      reason,
+      changed_by,
+      changed_at: Utc::now(),
+    });
+    account.status = status;
+    *self = account;
+    Ok(transaction)
+  }
+
+  fn erase_personal_data(&mut self, erased_by: u32) -> Result<Vec<String>, String> {
+    self.check_usable()?;
+    Ok(self.scrub_personal_data(erased_by))
+  }
+
+  fn export_personal_data(&self, merged_accounts: &[Account]) -> Result<String, String> {
+    serde_json::to_string_pretty(&PersonalDataExport {
+      exported_at: Utc::now(),
+      account: self,
+      merged_accounts,
+    })
+    .map_err(|e| format!("Hiba az adatok exportálása során: {}", e))
+  }
+
+  fn leave_household(&mut self) -> Result<&Self, String> {
+    if self.household_id.is_none() {
+      return Err("A fiók nem tagja háztartásnak!".to_string());
+    }
+    // Tier reached together is kept until requalification
+    self.household_id = None;
+    self.household_turnover_by_year = BTreeMap::new();
+    Ok(self)
+  }
+
+  fn burn_points(
+    &mut self,
+    purchase_id: Uuid,
+    points_to_burn: Points,
+    created_by: u32,
+  ) -> Result<Transaction, String> {
+    self.check_usable()?;
+    self.check_active()?;
+    // Zero burn would block the real one of the purchase
+    if points_to_burn.is_zero() {
+      return Err("A beváltott pontszám nem lehet nulla!".to_string());
+    }
+    // Closed purchase cannot be changed anymore
+    if self.is_purchase_closed(purchase_id) {
+      return Err("A vásárlás már lezárult, pont nem váltható be!".to_string());
+    }
+
+    // One burn per purchase; a repeated request with the same amount
+    // (e.g. POS retry) returns the original transaction
+    let burn_ids = self.get_active_burn_ids(purchase_id);
+    if let Some(burn) = self
+      .transactions
+      .iter()
+      .find(|t| burn_ids.contains(&t.transaction_id))
+    {
+      if burn_ids.len() == 1 && burn.amount == points_to_burn {
+        return Ok(burn.clone());
+      }
+      return Err(format!(
+        "A vásárláshoz már tartozik pontbeváltás eltérő összeggel: {}",
+        burn.amount
+      ));
+    }
+
+    // Lapsed points cannot be burned
+    self.expire_points(Utc::now())?;
+
+    // Points reserved at other tills cannot be burned
+    if self.get_spendable_balance() < points_to_burn.value() {
+      return Err(format!(
+        "Nincs elég pont a tranzakcióhoz. Jelenlegi pont: {}",
+        self.get_spendable_balance()
+      ));
+    }
+
+    // Return Ok transaction
+    self.push_burn(purchase_id, points_to_burn, created_by)
+  }
+
+  fn reserve_points(
+    &mut self,
+    purchase_id: Uuid,
+    points_to_reserve: Points,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<Reservation, String> {
+    self.check_usable()?;
+    self.check_active()?;
+    if self.is_purchase_closed(purchase_id) {
+      return Err("A vásárlás már lezárult, pont nem foglalható!".to_string());
+    }
+
+    // Lapsed points cannot be reserved
+    self.expire_points(Utc::now())?;
+
+    if points_to_reserve.is_zero() || self.get_spendable_balance() < points_to_reserve.value() {
+      return Err(format!(
+        "Nincs elég pont a foglaláshoz. Jelenlegi pont: {}",
+        self.get_spendable_balance()
+      ));
+    }
+
+    let created_at = Utc::now();
+    let reservation = Reservation {
+      reservation_id: Uuid::new_v4(),
+      purchase_id,
+      amount: points_to_reserve,
+      created_by,
+      created_at,
+      expires_at: created_at + config.reservation_timeout(),
+    };
+
+    self.reservations.push(reservation.clone());
+
+    Ok(reservation)
+  }
+
+  fn release_reservation(&mut self, purchase_id: Uuid) -> Result<Points, String> {
+    let released = Points::checked_sum(
+      self
+        .reservations
+        .iter()
+        .filter(|r| r.purchase_id == purchase_id)
+        .map(|r| r.amount),
+    )?;
+    if released.is_zero() {
+      return Err("A vásárláshoz nem tartozik pontfoglalás!".to_string());
+    }
+    self.reservations.retain(|r| r.purchase_id != purchase_id);
+    Ok(released)
+  }
+
+  fn get_reserved_points(&self, now: DateTime<Utc>) -> i32 {
+    // Timed out reservations do not hold points anymore,
+    // even if the release job has not removed them yet
+    self
+      .reservations
+      .iter()
+      .filter(|r| r.expires_at > now)
+      .fold(0, |acc, r| acc.saturating_add(r.amount.value()))
+  }
+
+  fn get_spendable_balance(&self) -> i32 {
+    let now = Utc::now();
+    self
+      .get_available_balance(now)
+      .saturating_sub(self.get_reserved_points(now))
+  }
+
+  fn get_pending_balance(&self, now: DateTime<Utc>) -> i32 {
+    self
+      .point_lots
+      .iter()
+      .filter(|lot| lot.available_at > now)
+      .fold(0, |acc, lot| acc.saturating_add(lot.remaining().value()))
+  }
+
+  fn get_available_balance(&self, now: DateTime<Utc>) -> i32 {
+    self
+      .get_balance()
+      .saturating_sub(self.get_pending_balance(now))
+  }
+
+  fn has_expired_reservations(&self, now: DateTime<Utc>) -> bool {
+    self.reservations.iter().any(|r| r.expires_at <= now)
+  }
+
+  fn release_expired_reservations(&mut self, now: DateTime<Utc>) -> Vec<Reservation> {
+    let (expired, active) = self
+      .reservations
+      .drain(..)
+      .partition(|r| r.expires_at <= now);
+    self.reservations = active;
+    expired
+  }
+
+  fn cancel_burn(&mut self, purchase_id: Uuid, created_by: u32) -> Result<Transaction, String> {
+    self.check_usable()?;
+    self.check_active()?;
+    // Closed purchase cannot be changed anymore
+    if self.is_purchase_closed(purchase_id) {
+      return Err("A vásárlás már lezárult, a pontbeváltás nem vonható vissza!".to_string());
+    }
+
+    // Burns of the purchase not cancelled yet
+    let burn_ids = self.get_active_burn_ids(purchase_id);
+    if burn_ids.is_empty() {
+      return Err("A vásárláshoz nem tartozik visszavonható pontbeváltás!".to_string());
+    }
+    let points_to_restore = Points::checked_sum(
+      self
+        .transactions
+        .iter()
+        .filter(|t| burn_ids.contains(&t.transaction_id))
+        .map(|t| t.amount),
+    )?;
+    let balance = points_to_restore.credit(self.balance_points)?;
+
+    let transaction = Transaction::new(
+      purchase_id,
+      self.account_id,
+      TransactionKind::BurnCancel {
+        burn_transaction_ids: burn_ids.clone(),
+      },
+      points_to_restore,
+      created_by,
+    );
+
+    // Give back the burned points to the lots they were taken from
+    for lot in self.point_lots.iter_mut() {
+      lot
+        .usages
+        .retain(|usage| !burn_ids.contains(&usage.transaction_id));
+    }
+
+    // Update balance
+    self.balance_points = balance;
+
+    self.transactions.push(transaction.clone());
+
+    Ok(transaction)
+  }
+
+  fn close_purchase(
+    &mut self,
+    purchase_info: PurchaseInfo,
+    config: &LoyaltyConfig,
+    campaigns: &[Campaign],
+    created_by: u32,
+  ) -> Result<PurchaseSummary, String> {
+    self.check_usable()?;
+    // Repeated close with the same data (e.g. POS retry)
+    // returns the original summary
+    if let Some(closed) = self
+      .closed_purchases
+      .iter()
+      .find(|p| p.purchase_id == purchase_info.purchase_id)
+    {
+      if closed.payable_total_gross == purchase_info.payable_total_gross {
+        return Ok(closed.summary.clone());
+      }
+      return Err(format!(
+        "A vásárlás már lezárult eltérő összeggel: {}",
+        closed.payable_total_gross
+      ));
+    }
+    if self.is_purchase_closed(purchase_info.purchase_id) {
+      return Err("A vásárlás már lezárult!".to_string());
+    }
+    // Retries above are still answered
+    self.check_active()?;
+
+    // Commit the points reserved for this purchase
+    // Timed out reservations not released yet are committed too,
+    // the discount was given for them; unless the points are gone
+    let now = Utc::now();
+    self.expire_points(now)?;
+    let reserved = Points::checked_sum(
+      self
+        .reservations
+        .iter()
+        .filter(|r| r.purchase_id == purchase_info.purchase_id)
+        .map(|r| r.amount),
+    )?;
+    let held_elsewhere = Points::checked_sum(
+      self
+        .reservations
+        .iter()
+        .filter(|r| r.purchase_id != purchase_info.purchase_id && r.expires_at > now)
+        .map(|r| r.amount),
+    )?;
+    if reserved.value()
+      > self
+        .get_available_balance(now)
+        .saturating_sub(held_elsewhere.value())
+    {
+      return Err("A lefoglalt pontok már nem állnak rendelkezésre!".to_string());
+    }
+    self
+      .reservations
+      .retain(|r| r.purchase_id != purchase_info.purchase_id);
+    if !reserved.is_zero() {
+      self.push_burn(purchase_info.purchase_id, reserved, created_by)?;
+    }
+
+    // Check if we should upgrade loyalty level
+    self.check_loyalty_level(config);
+
+    // Get earn rate of the current tier
+    let rate = self.loyalty_level.get_earn_rate(config)?;
+
+    // Calculate points to earn
+    let rounding = config.rounding;
+    let mut points_to_earn = rate.apply(purchase_info.payable_total_gross, rounding)?;
+
+    // Best running campaign adds its extra points
+    let campaign = campaign::best_campaign(
+      campaigns,
+      now.naive_utc().date(),
+      &self.loyalty_level,
+      points_to_earn,
+      rounding,
+    )?;
+    if let Some((_, extra)) = campaign {
+      points_to_earn = points_to_earn.checked_add(extra)?;
+    }
+    let birthday_reward = self.get_birthday_reward(points_to_earn, config, now)?;
+
+    // Check overflows before any change
+    let balance = points_to_earn.credit(self.balance_points)?;
+    if let Some((_, bonus)) = birthday_reward {
+      bonus.credit(balance)?;
+    }
+    let year = Utc::today().year();
+    let turnover = self
+      .gross_turnover_by_year
+      .get(&year)
+      .cloned()
+      .unwrap_or_default()
+      .checked_add(purchase_info.payable_total_gross)?;
+
+    // Create transaction
+    let transaction = Transaction::new(
+      purchase_info.purchase_id,
+      self.account_id,
+      TransactionKind::Earn {
+        total_payable_amount: purchase_info.payable_total_gross,
+        rate,
+        rounding,
+        campaign_id: campaign.map(|(c, _)| c.campaign_id),
+      },
+      points_to_earn,
+      created_by,
+    );
+
+    // Store transaction with its lot and update balance
+    self.push_earning(transaction, config)?;
+
+    // Birthday reward as its own transaction
+    let mut bonus_points = Points::zero();
+    if let Some((year, bonus)) = birthday_reward {
+      let transaction = Transaction::new(
+        purchase_info.purchase_id,
+        self.account_id,
+        TransactionKind::Birthday { year },
+        bonus,
+        created_by,
+      );
+      self.push_earning(transaction, config)?;
+      bonus_points = bonus;
+    }
+
+    // Update this year's turnover
+    self.gross_turnover_by_year.insert(year, turnover);
+
+    // Check if we should upgrade loyalty level
+    self.check_loyalty_level(config);
+
+    let burned_points = self.get_burned_points(purchase_info.purchase_id);
+    // Birthday reward counts as earned on this purchase
+    let earned_points = points_to_earn.value() + bonus_points.value();
+    let balance_closing = self.get_balance();
+    let balance_opening = balance_closing - earned_points + burned_points;
+
+    let summary = PurchaseSummary {
+      balance_opening,
+      burned_points,
+      earned_points,
+      balance_closing,
+    };
+
+    // Store summary for repeated requests
+    self.closed_purchases.push(ClosedPurchase {
+      purchase_id: purchase_info.purchase_id,
+      payable_total_gross: purchase_info.payable_total_gross,
+      summary: summary.clone(),
+      closed_at: Utc::now(),
+    });
+
+    Ok(summary)
+  }
+
+  fn refund_purchase(
+    &mut self,
+    purchase_id: Uuid,
+    refund_amount: Money,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<Transaction, String> {
+    self.check_usable()?;
+    // Find the original earn transaction
+    let (earn_id, earn_year, earned_points, total_payable_amount, rounding) = self
+      .transactions
+      .iter()
+      .find_map(|t| match t.transaction_kind {
+        TransactionKind::Earn {
+          total_payable_amount,
+          rate: _,
+          rounding,
+          campaign_id: _,
+        } if t.purchase_id == purchase_id => Some((
+          t.transaction_id,
+          t.created_at.year(),
+          t.amount,
+          total_payable_amount,
+          rounding,
+        )),
+        _ => None,
+      })
+      .ok_or("A megadott vásárláshoz nem tartozik pontgyűjtés!".to_string())?;
+
+    // Earlier refunds of the same purchase
+    let refunds = self
+      .transactions
+      .iter()
+      .filter_map(|t| match t.transaction_kind {
+        TransactionKind::Refund {
+          earn_transaction_id,
+          refund_amount,
+          rounding: _,
+        } if earn_transaction_id == earn_id => Some((refund_amount, t.amount)),
+        _ => None,
+      })
+      .collect::<Vec<(Money, Points)>>();
+    let refundable_amount =
+      total_payable_amount.checked_sub(Money::checked_sum(refunds.iter().map(|r| r.0))?)?;
+    let reversible_points =
+      earned_points.checked_sub(Points::checked_sum(refunds.iter().map(|r| r.1))?)?;
+
+    if refund_amount.is_zero() || refund_amount > refundable_amount {
+      return Err(format!(
+        "Hibás visszatérítési összeg! Maximum visszatéríthető: {}",
+        refundable_amount
+      ));
+    }
+
+    // Points to take back in proportion of the refunded amount,
+    // rounded the same way as at earning;
+    // the last refund takes back all the rest
+    let points_to_reverse = if refund_amount == refundable_amount {
+      reversible_points
+    } else {
+      std::cmp::min(
+        rounding.proportion(earned_points, refund_amount, total_payable_amount)?,
+        reversible_points,
+      )
+    };
+
+    // Birthday reward of the purchase is taken back the same way
+    let birthday_to_reverse = match self.transactions.iter().find(|t| {
+      t.purchase_id == purchase_id && matches!(t.transaction_kind, TransactionKind::Birthday { .. })
+    }) {
+      Some(birthday) => {
+        let kept = self.kept_birthday_points(birthday)?;
+        let points = if refund_amount == refundable_amount {
+          kept
+        } else {
+          std::cmp::min(
+            rounding.proportion(birthday.amount, refund_amount, total_payable_amount)?,
+            kept,
+          )
+        };
+        Some((birthday.transaction_id, points))
+      }
+      None => None,
+    };
+
+    // It can go below zero if the earned points are already burned
+    let mut balance = points_to_reverse.debit(self.balance_points)?;
+    if let Some((_, points)) = birthday_to_reverse {
+      balance = points.debit(balance)?;
+    }
+
+    let transaction = Transaction::new(
+      purchase_id,
+      self.account_id,
+      TransactionKind::Refund {
+        earn_transaction_id: earn_id,
+        refund_amount,
+        rounding,
+      },
+      points_to_reverse,
+      created_by,
+    );
+
+    // Update balance
+    self.balance_points = balance;
+
+    // Take the points back from the original lot first
+    self.consume_lots(
+      transaction.transaction_id,
+      points_to_reverse,
+      Some(earn_id),
+      LotSource::Available,
+    )?;
+
+    // Refunded amount does not count toward the tier anymore
+    // Purchases before per-year turnover may be missing from the bucket
+    let turnover = self
+      .gross_turnover_by_year
+      .entry(earn_year)
+      .or_insert_with(Money::zero);
+    *turnover = turnover.checked_sub(refund_amount).unwrap_or_default();
+
+    self.transactions.push(transaction.clone());
+
+    // Birthday reversal as its own transaction, like the reward itself
+    if let Some((birthday_id, points)) = birthday_to_reverse {
+      if !points.is_zero() {
+        let reversal = Transaction::new(
+          purchase_id,
+          self.account_id,
+          TransactionKind::BirthdayRefund {
+            birthday_transaction_id: birthday_id,
+          },
+          points,
+          created_by,
+        );
+        self.consume_lots(
+          reversal.transaction_id,
+          points,
+          Some(birthday_id),
+          LotSource::Available,
+        )?;
+        self.transactions.push(reversal);
+      }
+    }
+
+    // Recompute tier eligibility
+    self.check_loyalty_level_after_refund(purchase_id, config);
+
+    Ok(transaction)
+  }
+
+  fn adjust_points(
+    &mut self,
+    amount: i32,
+    reason: AdjustmentReason,
+    notes: String,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<Transaction, String> {
+    self.check_usable()?;
+    self.check_active()?;
+    self.push_adjustment(amount, reason, notes, config, created_by)
+  }
+
+  fn request_adjustment(
+    &mut self,
+    amount: i32,
+    reason: AdjustmentReason,
+    notes: String,
+    config: &LoyaltyConfig,
+    created_by: u32,
+  ) -> Result<AdjustmentOutcome, String> {
+    self.check_usable()?;
+    self.check_active()?;
+    self.queue_adjustment(amount, reason, notes, config, created_by)
+  }
+
+  fn approve_adjustment(
+    &mut self,
+    request_id: Uuid,
+    approved_by: u32,
+    config: &LoyaltyConfig,
+  ) -> Result<Transaction, String> {
+    self.check_usable()?;
+    let request = self.get_pending_adjustment_request(request_id, approved_by)?;
+    // Payout of the remaining points can be approved after closing
+    if !(self.status == AccountStatus::Closed && request.reason == AdjustmentReason::PayOut) {
+      self.check_active()?;
+    }
+    let transaction = self.push_adjustment(
+      request.amount,
+      request.reason,
+      request.notes,
+      config,
+      approved_by,
+    )?;
+    self.decide_adjustment_request(
+      request_id,
+      AdjustmentStatus::Approved {
+        transaction_id: transaction.transaction_id,
+      },
+      approved_by,
+    );
+    Ok(transaction)
+  }
+
+  fn reject_adjustment(
+    &mut self,
+    request_id: Uuid,
+    rejected_by: u32,
+  ) -> Result<AdjustmentRequest, String> {
+    self.get_pending_adjustment_request(request_id, rejected_by)?;
+    Ok(self.decide_adjustment_request(request_id, AdjustmentStatus::Rejected, rejected_by))
+  }
+
+  fn has_expired_adjustment_requests(&self, now: DateTime<Utc>) -> bool {
+    self
+      .adjustment_requests
+      .iter()
+      .any(|r| r.status == AdjustmentStatus::Pending && r.expires_at <= now)
+  }
+
+  fn expire_adjustment_requests(&mut self, now: DateTime<Utc>) -> Vec<AdjustmentRequest> {
+    let mut expired = Vec::new();
+    for request in self.adjustment_requests.iter_mut() {
+      if request.status == AdjustmentStatus::Pending && request.expires_at <= now {
+        request.status = AdjustmentStatus::Expired;
+        request.decided_at = Some(now);
+        expired.push(request.clone());
+      }
+    }
+    expired
+  }
+
+  fn get_balance(&self) -> i32 {
+    self.balance_points
+  }
+
+  fn check_loyalty_level(&mut self, config: &LoyaltyConfig) {
+    // Highest tier the yearly total qualifies for
+    let turnover = self.get_qualifying_turnover_of_year(Utc::today().year());
+    let eligible = config.level_for_turnover(turnover);
+    match (config.rank(&self.loyalty_level), config.rank(&eligible)) {
+      // Only upgrade here, never downgrade
+      (Some(current), Some(target)) if current > target => (),
+      // Current tier reached again, cancel scheduled downgrade
+      (Some(current), Some(target)) if current == target => self.pending_downgrade = None,
+      // Upgrade, or current tier is not configured anymore
+      _ => {
+        self.pending_downgrade = None;
+        self.change_loyalty_level(
+          eligible,
+          LevelChangeReason::Turnover {
+            year: Utc::today().year(),
+            turnover,
+          },
+        );
+      }
+    }
+  }
+
+  fn is_requalification_due(&self, config: &LoyaltyConfig, now: DateTime<Utc>) -> bool {
+    let requalification_due = now >= config.requalification.date_of_year(now.year())
+      && self
+        .last_requalification
+        .is_none_or(|year| year < now.year());
+    let downgrade_due = self
+      .pending_downgrade
+      .as_ref()
+      .is_some_and(|downgrade| downgrade.effective_at <= now);
+    requalification_due || downgrade_due
+  }
+
+  fn requalify(&mut self, config: &LoyaltyConfig, now: DateTime<Utc>) {
+    // Apply downgrade scheduled by an earlier requalification
+    // if its grace period is over
+    if let Some(downgrade) = self.pending_downgrade.clone() {
+      if downgrade.effective_at <= now {
+        self.pending_downgrade = None;
+        // This year's turnover may still qualify for something better
+        let eligible = config.level_for_turnover(self.get_qualifying_turnover_of_year(now.year()));
+        if config.rank(&eligible) > config.rank(&downgrade.loyalty_level) {
+          self.change_loyalty_level(eligible, downgrade.reason);
+        } else {
+          self.change_loyalty_level(downgrade.loyalty_level, downgrade.reason);
+        }
+      }
+    }
+
+    let year = now.year();
+    let rollover = config.requalification.date_of_year(year);
+    if now < rollover
+      || self
+        .last_requalification
+        .is_some_and(|last_year| last_year >= year)
+    {
+      return;
+    }
+    self.last_requalification = Some(year);
+
+    // Re-evaluate against the previous year's turnover
+    let turnover = self.get_qualifying_turnover_of_year(year - 1);
+    let eligible = config.level_for_turnover(turnover);
+    let reason = LevelChangeReason::Requalification {
+      year: year - 1,
+      turnover,
+    };
+    match (config.rank(&self.loyalty_level), config.rank(&eligible)) {
+      (Some(current), Some(target)) if current == target => self.pending_downgrade = None,
+      // Downgrade with grace period
+      (Some(current), Some(target))
+        if current > target && config.requalification.grace_period_days > 0 =>
+      {
+        self.pending_downgrade = Some(PendingDowngrade {
+          loyalty_level: eligible,
+          effective_at: rollover + config.requalification.grace_period(),
+          reason,
+        })
+      }
+      // Upgrade, or downgrade without grace period
+      _ => {
+        self.pending_downgrade = None;
+        self.change_loyalty_level(eligible, reason);
+      }
+    }
+  }
+
+  fn get_burned_points(&mut self, purchase_id: Uuid) -> i32 {
+    self.transactions.iter().fold(0, |acc, t| {
+      if t.purchase_id == purchase_id {
+        match t.transaction_kind {
+          TransactionKind::Burn => return acc + t.amount.value(),
+          TransactionKind::BurnCancel { .. } => return acc - t.amount.value(),
+          _ => return acc,
+        }
+      }
+      acc
+    })
+  }
+
+  fn has_expired_points(&self, now: DateTime<Utc>) -> bool {
+    self
+      .point_lots
+      .iter()
+      .any(|lot| lot.expires_at <= now && !lot.remaining().is_zero())
+  }
+
+  fn expire_points(&mut self, now: DateTime<Utc>) -> Result<Vec<Transaction>, String> {
+    let mut expired = Vec::new();
+    for lot in self.point_lots.iter_mut() {
+      let remaining = lot.remaining();
+      if lot.expires_at > now || remaining.is_zero() {
+        continue;
+      }
+      // Expiry is not related to any purchase
+      let transaction = Transaction::new(
+        Uuid::default(),
+        self.account_id,
+        TransactionKind::Expire { lot_id: lot.lot_id },
+        remaining,
+        0,
+      );
+      lot.usages.push(LotUsage {
+        transaction_id: transaction.transaction_id,
+        amount: remaining,
+      });
+      self.balance_points = remaining.debit(self.balance_points)?;
+      expired.push(transaction);
+    }
+    // Expired lots are empty now, no need to keep them
+    self.point_lots.retain(|lot| lot.expires_at > now);
+    self.transactions.extend(expired.iter().cloned());
+    Ok(expired)
+  }
+
+  fn get_yearly_gross_turnover(&self) -> i32 {
+    self.get_gross_turnover_of_year(Utc::today().naive_local().year())
+  }
+
+  fn get_gross_turnover_of_year(&self, year: i32) -> i32 {
+    self
+      .gross_turnover_by_year
+      .get(&year)
+      .map(|turnover| turnover.value())
+      .unwrap_or(0)
+  }
+
+  fn get_qualifying_turnover_of_year(&self, year: i32) -> i32 {
+    // Household members qualify together
+    let household = self
+      .household_turnover_by_year
+      .get(&year)
+      .map(|turnover| turnover.value())
+      .unwrap_or(0);
+    self
+      .get_gross_turnover_of_year(year)
+      .saturating_add(household)
+  }
+
+  fn needs_upgrade(&self) -> bool {
+    self.version < ACCOUNT_VERSION
+      // Stored before multiple cards
+      || self.legacy_card_id.is_some()
+  }
+
+  fn upgrade(&mut self, config: &LoyaltyConfig, now: DateTime<Utc>) -> Result<(), String> {
+    // Stored before per-year turnover
+    if self.version < 1 {
+      self.gross_turnover_by_year = self.rebuild_turnover_by_year()?;
+    }
+    // Stored before point lots: the balance not covered by lots
+    // becomes a lot of its own, expiring a full period after the upgrade
+    // as its earning dates are not known
+    if self.version < 2 {
+      let covered = Points::checked_sum(self.point_lots.iter().map(|lot| lot.remaining()))?;
+      let uncovered = self.balance_points - covered.value();
+      if uncovered > 0 {
+        self.point_lots.push(PointLot {
+          lot_id: Uuid::new_v4(),
+          amount: Points::new(uncovered)?,
+          usages: Vec::new(),
+          created_at: now,
+          available_at: now,
+          expires_at: config.point_expiry_of(now),
+        });
+        self.point_lots.sort_by_key(|lot| lot.expires_at);
+      }
+    }
+    // The single card was in use, it was checked when set
+    if let Some(card_id) = self.legacy_card_id.take() {
+      if self.get_card(&card_id).is_none() {
+        self.cards.push(Card {
+          card_id,
+          state: CardState::Active,
+          reason: "".to_string(),
+          issued_at: self.created_at,
+          retired_at: None,
+        });
+      }
+    }
+    self.version = ACCOUNT_VERSION;
+    Ok(())
+  }
+}
+
+impl Default for Account {
+  fn default() -> Self {
+    Self {
+      account_id: Uuid::default(),
+      version: ACCOUNT_VERSION,
+      customer_id: None,
+      customer_birthdate: None,
+      cards: Vec::new(),
+      legacy_card_id: None,
+      loyalty_level: LoyaltyLevel::default(),
+      balance_points: 0,
+      gross_turnover_by_year: BTreeMap::new(),
+      transactions: Vec::new(),
+      level_changes: Vec::new(),
+      // Nothing to requalify for until next year
+      last_requalification: requalified_this_year(),
+      pending_downgrade: None,
+      point_lots: Vec::new(),
+      reservations: Vec::new(),
+      closed_purchases: Vec::new(),
+      adjustment_requests: Vec::new(),
+      household_id: None,
+      household_turnover_by_year: BTreeMap::new(),
+      merged_into: None,
+      merges: Vec::new(),
+      status: AccountStatus::Active,
+      status_changes: Vec::new(),
+      erased_by: None,
+      erased_at: None,
+      created_by: 0,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+// Accounts are first requalified in the year after they were created
+// Accounts stored before yearly requalification are treated the same,
+// so they are not re-evaluated mid-year against an incomplete last year
+fn requalified_this_year() -> Option<i32> {
+  Some(Utc::today().year())
+}
+
+impl VecPackMember for Account {
+  type Out = Uuid;
+
+  fn get_id(&self) -> &Self::Out {
+    &self.account_id
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CardState {
+  Issued,   // Handed over, not usable yet
+  Active,   // Usable to identify the account
+  Lost,     // Reported lost, final
+  Blocked,  // Temporarily not usable, can be activated again
+  Replaced, // A new card was issued instead, final
+}
+
+impl CardState {
+  const ALL: [CardState; 5] = [
+    CardState::Issued,
+    CardState::Active,
+    CardState::Lost,
+    CardState::Blocked,
+    CardState::Replaced,
+  ];
+
+  /// Check if the card can move to the given state
+  pub fn can_change_to(&self, state: CardState) -> bool {
+    matches!(
+      (self, state),
+      (CardState::Issued, CardState::Active)
+        | (CardState::Issued, CardState::Lost)
+        | (CardState::Issued, CardState::Blocked)
+        | (CardState::Active, CardState::Lost)
+        | (CardState::Active, CardState::Blocked)
+        | (CardState::Active, CardState::Replaced)
+        | (CardState::Blocked, CardState::Active)
+        | (CardState::Blocked, CardState::Lost)
+        | (CardState::Blocked, CardState::Replaced)
+    )
+  }
+
+  /// Check if the card is out of use
+  pub fn is_retired(&self) -> bool {
+    matches!(
+      self,
+      CardState::Lost | CardState::Blocked | CardState::Replaced
+    )
+  }
+}
+
+impl std::str::FromStr for CardState {
+  type Err = String;
+  fn from_str(str: &str) -> Result<Self, Self::Err> {
+    Self::ALL
+      .iter()
+      .find(|s| s.to_string().eq_ignore_ascii_case(str))
+      .cloned()
+      .ok_or(format!(
+        "Nem megfelelő kártya állapot! Lehetséges értékek: {}",
+        Self::ALL
+          .iter()
+          .map(|s| s.to_string())
+          .collect::<Vec<String>>()
+          .join(", ")
+      ))
+  }
+}
+
+impl std::fmt::Display for CardState {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}", self)
+  }
+}
+
+/// Card ID in the form it is stored and indexed
+/// Fails if the ID does not pass the Luhn check
+pub fn normalize_card_id(card_id: &str) -> Result<String, String> {
+  card_id
+    .to_string()
+    .luhn_check()
+    .map_err(|_| "A megadott kártya azonosító nem valid!".to_string())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Card {
+  pub card_id: String,
+  pub state: CardState,
+  pub reason: String, // Reason of the last state change
+  pub issued_at: DateTime<Utc>,
+  pub retired_at: Option<DateTime<Utc>>, // Set while lost, blocked or replaced
+}
+
+impl Card {
+  fn change_state(&mut self, state: CardState, reason: String) -> Result<(), String> {
+    if !self.state.can_change_to(state) {
+      return Err(format!(
+        "A kártya nem állítható {:?} állapotból {:?} állapotba!",
+        self.state, state
+      ));
+    }
+    self.retired_at = match state.is_retired() {
+      true => Some(Utc::now()),
+      false => None,
+    };
+    self.state = state;
+    self.reason = reason;
+    Ok(())
+  }
+}
+
+// Tier ID from the configured tier table
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct LoyaltyLevel(String);
+
+impl LoyaltyLevel {
+  pub fn new(tier_id: &str) -> Self {
+    Self(tier_id.to_string())
+  }
+  pub fn as_str(&self) -> &str {
+    &self.0
+  }
+  pub fn get_earn_rate(&self, config: &LoyaltyConfig) -> Result<Rate, String> {
+    config
+      .get_tier(self)
+      .map(|tier| tier.earn_rate)
+      .ok_or(format!("Ismeretlen kedvezmény szint: {}", self.0))
+  }
+  pub fn from_str(str: &str, config: &LoyaltyConfig) -> Result<Self, String> {
+    // Accept both tier ID and display name
+    config
+      .tiers
+      .iter()
+      .find(|t| t.id.eq_ignore_ascii_case(str) || t.name.eq_ignore_ascii_case(str))
+      .map(|t| Self::new(&t.id))
+      .ok_or(format!(
+        "Nem megfelelő kedvezmény szint! Lehetséges értékek: {}",
+        config
+          .tiers
+          .iter()
+          .map(|t| t.id.as_str())
+          .collect::<Vec<&str>>()
+          .join(", ")
+      ))
+  }
+}
+
+impl ToString for LoyaltyLevel {
+  fn to_string(&self) -> String {
+    self.0.clone()
+  }
+}
+
+// Paired transactions of a point transfer
+pub struct Transfer {
+  pub transfer_id: Uuid,
+  pub debit: Transaction,
+  pub credit: Transaction,
+}
+
+/// Move points from one account to another
+/// Either both accounts change or none of them
+pub fn transfer_points(
+  from: &mut Account,
+  to: &mut Account,
+  points: Points,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<Transfer, String> {
+  move_points(from, to, points, false, config, created_by)
+}
+
+// Transfer, optionally as pooling without limits
+// (household members, carry over from an anonymous account)
+pub(crate) fn move_points(
+  from: &mut Account,
+  to: &mut Account,
+  points: Points,
+  pooled: bool,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<Transfer, String> {
+  move_lots(
+    from,
+    to,
+    points,
+    pooled,
+    LotSource::Available,
+    config,
+    created_by,
+  )
+}
+
+// Household pooling for a burn of the given purchase
+// Both sides are linked to the purchase, so cancelling the burn
+// can give the points back to the members they came from
+pub(crate) fn pool_points(
+  from: &mut Account,
+  to: &mut Account,
+  purchase_id: Uuid,
+  points: Points,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<Transfer, String> {
+  let mut transfer = move_points(from, to, points, true, config, created_by)?;
+  for (account, transaction) in [(from, &mut transfer.debit), (to, &mut transfer.credit)] {
+    transaction.purchase_id = purchase_id;
+    if let Some(stored) = account
+      .transactions
+      .iter_mut()
+      .find(|t| t.transaction_id == transaction.transaction_id)
+    {
+      stored.purchase_id = purchase_id;
+    }
+  }
+  Ok(transfer)
+}
+
+// Transfer taking the points from the given source of lots
+fn move_lots(
+  from: &mut Account,
+  to: &mut Account,
+  points: Points,
+  pooled: bool,
+  lot_source: LotSource,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<Transfer, String> {
+  if from.account_id == to.account_id {
+    return Err("Azonos fiókok között nem lehet pontot átvezetni!".to_string());
+  }
+  if points.is_zero() {
+    return Err("Az átvezetett pontszám nem lehet nulla!".to_string());
+  }
+  let transfer_id = Uuid::new_v4();
+  // Work on copies, write back only if both sides succeeded
+  let mut source = from.clone();
+  let mut target = to.clone();
+  // Household pooling is not limited
+  if !pooled {
+    source.check_transfer_limits(points, config, Utc::now())?;
+  }
+  let debit = source.transfer_out(
+    transfer_id,
+    target.account_id,
+    points,
+    pooled,
+    lot_source,
+    created_by,
+  )?;
+  // Availability and expiry of the transferred points
+  let mut lots = source
+    .point_lots
+    .iter()
+    .filter_map(|lot| {
+      lot
+        .usages
+        .iter()
+        .find(|u| u.transaction_id == debit.transaction_id)
+        .map(|u| (u.amount, lot.available_at, lot.expires_at))
+    })
+    .collect::<Vec<(Points, DateTime<Utc>, DateTime<Utc>)>>();
+  // Balance not covered by lots gets the default expiry
+  let covered = Points::checked_sum(lots.iter().map(|l| l.0))?;
+  if covered < points {
+    lots.push((
+      points.checked_sub(covered)?,
+      debit.created_at,
+      config.point_expiry_of(debit.created_at),
+    ));
+  }
+  let credit = target.transfer_in(transfer_id, source.account_id, lots, points, created_by)?;
+  *from = source;
+  *to = target;
+  Ok(Transfer {
+    transfer_id,
+    debit,
+    credit,
+  })
+}
+
+/// Carry over the spendable and pending points and the turnover
+/// of an anonymous account into the given account
+/// Points keep their availability and expiry, like in a household pooling
+pub fn carry_over(
+  from: &mut Account,
+  to: &mut Account,
+  config: &LoyaltyConfig,
+  created_by: u32,
+) -> Result<Option<Transfer>, String> {
+  if !from.is_anonymous() {
+    return Err("Csak névtelen fiók pontjai vihetők át!".to_string());
+  }
+  // Work on copies, write back only if everything succeeded
+  let mut source = from.clone();
+  let mut target = to.clone();
+  let now = Utc::now();
+  source.expire_points(now)?;
+  let points = source
+    .get_spendable_balance()
+    .saturating_add(source.get_pending_balance(now));
+  let transfer = match points {
+    points if points > 0 => Some(move_lots(
+      &mut source,
+      &mut target,
+      Points::new(points)?,
+      true,
+      LotSource::AvailableFirst,
+      config,
+      created_by,
+    )?),
+    _ => None,
+  };
+  for (year, turnover) in std::mem::take(&mut source.gross_turnover_by_year) {
+    let total = target
+      .gross_turnover_by_year
+      .entry(year)
+      .or_insert_with(Money::zero);
+    *total = total.checked_add(turnover)?;
+  }
+  target.check_loyalty_level(config);
+  *from = source;
+  *to = target;
+  Ok(transfer)
+}
+
+/// Merge an account of the same customer into the surviving one
+/// Transactions, points, turnover, purchases and cards move over,
+/// the merged account is left as a tombstone pointing to the survivor
+/// Either both accounts change or none of them
+pub fn merge_accounts(
+  survivor: &mut Account,
+  merged: &mut Account,
+  config: &LoyaltyConfig,
+  merged_by: u32,
+) -> Result<AccountMerge, String> {
+  if survivor.account_id == merged.account_id {
+    return Err("Egy fiók nem olvasztható önmagába!".to_string());
+  }
+  survivor.check_usable()?;
+  merged.check_usable()?;
+  // Points cannot be moved out of or into a suspended or closed account
+  survivor.check_active()?;
+  merged.check_active()?;
+  if let (Some(a), Some(b)) = (survivor.customer_id, merged.customer_id) {
+    if a != b {
+      return Err("Csak azonos vásárló fiókjai olvaszthatók össze!".to_string());
+    }
+  }
+  let now = Utc::now();
+  // Open items would point to the merged account
+  if merged.get_reserved_points(now) > 0 {
+    return Err("A beolvasztandó fióknak nyitott pontfoglalása van!".to_string());
+  }
+  if merged
+    .adjustment_requests
+    .iter()
+    .any(|r| r.status == AdjustmentStatus::Pending && r.expires_at > now)
+  {
+    return Err("A beolvasztandó fióknak függő korrekciós kérése van!".to_string());
+  }
+  if merged.household_id.is_some() {
+    return Err("A beolvasztandó fiók háztartás tagja, előbb ki kell lépnie!".to_string());
+  }
+  // Work on copies, write back only if everything succeeded
+  let mut target = survivor.clone();
+  let mut source = merged.clone();
+  let merge = AccountMerge {
+    merge_id: Uuid::new_v4(),
+    from_account_id: source.account_id,
+    into_account_id: target.account_id,
+    balance_points: source.balance_points,
+    transaction_count: source.transactions.len(),
+    card_ids: source.cards.iter().map(|c| c.card_id.clone()).collect(),
+    merged_by,
+    merged_at: now,
+  };
+  target.balance_points = target
+    .balance_points
+    .checked_add(source.balance_points)
+    .ok_or("Pontegyenleg túlcsordulás!".to_string())?;
+  for mut transaction in std::mem::take(&mut source.transactions) {
+    transaction.account_id = target.account_id;
+    target.transactions.push(transaction);
+  }
+  target.transactions.sort_by_key(|t| t.created_at);
+  target.point_lots.append(&mut source.point_lots);
+  // Keep lots in expiry order, so the soonest expiring is burned first
+  target.point_lots.sort_by_key(|lot| lot.expires_at);
+  for (year, turnover) in std::mem::take(&mut source.gross_turnover_by_year) {
+    let total = target
+      .gross_turnover_by_year
+      .entry(year)
+      .or_insert_with(Money::zero);
+    *total = total.checked_add(turnover)?;
+  }
+  target.closed_purchases.append(&mut source.closed_purchases);
+  target.cards.append(&mut source.cards);
+  // Survivor takes over the customer of an anonymous account
+  if target.is_anonymous() {
+    target.customer_id = source.customer_id;
+    target.customer_birthdate = source.customer_birthdate;
+  }
+  target.check_loyalty_level(config);
+  target.merges.push(merge.clone());
+  // Tombstone keeps no customer data, so customer lookups find the survivor
+  source.balance_points = 0;
+  source.customer_id = None;
+  source.customer_birthdate = None;
+  source.merged_into = Some(target.account_id);
+  source.merges.push(merge.clone());
+  *survivor = target;
+  *merged = source;
+  Ok(merge)
+}
+
+/// Erase the personal data left on the tombstone of a merged account
+/// Done along with the account it was merged into
+pub fn erase_tombstone(tombstone: &mut Account, erased_by: u32) -> Result<Vec<String>, String> {
+  if tombstone.merged_into.is_none() {
+    return Err("A fiók nem beolvasztott fiók!".to_string());
+  }
+  Ok(tombstone.scrub_personal_data(erased_by))
+}
+
+// Check a manual adjustment before applying or requesting it
+fn check_adjustment(amount: i32, reason: AdjustmentReason, notes: &str) -> Result<(), String> {
+  if amount == 0 {
+    return Err("A korrekció összege nem lehet nulla!".to_string());
+  }
+  if reason == AdjustmentReason::Other && notes.trim().is_empty() {
+    return Err("Egyéb ok esetén a megjegyzés kötelező!".to_string());
+  }
+  Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+pub enum AccountStatus {
+  #[default]
+  Active,
+  Suspended, // Temporarily cannot collect or burn points
+  Closed,    // Remaining points forfeited or paid out; can be reopened
+}
+
+impl AccountStatus {
+  const ALL: [AccountStatus; 3] = [
+    AccountStatus::Active,
+    AccountStatus::Suspended,
+    AccountStatus::Closed,
+  ];
+
+  /// Check if the account can move to the given status
+  pub fn can_change_to(&self, status: AccountStatus) -> bool {
+    matches!(
+      (self, status),
+      (AccountStatus::Active, AccountStatus::Suspended)
+        | (AccountStatus::Active, AccountStatus::Closed)
+        | (AccountStatus::Suspended, AccountStatus::Active)
+        | (AccountStatus::Suspended, AccountStatus::Closed)
+        | (AccountStatus::Closed, AccountStatus::Active)
+    )
+  }
+}
+
+impl std::str::FromStr for AccountStatus {
+  type Err = String;
+  fn from_str(str: &str) -> Result<Self, Self::Err> {
+    Self::ALL
+      .iter()
+      .find(|s| s.to_string().eq_ignore_ascii_case(str))
+      .cloned()
+      .ok_or(format!(
+        "Nem megfelelő fiók állapot! Lehetséges értékek: {}",
+        Self::ALL
+          .iter()
+          .map(|s| s.to_string())
+          .collect::<Vec<String>>()
+          .join(", ")
+      ))
+  }
+}
+
+impl std::fmt::Display for AccountStatus {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}", self)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StatusChange {
+  pub from: AccountStatus,
+  pub to: AccountStatus,
+  pub reason: String,
+  pub changed_by: u32,
+  pub changed_at: DateTime<Utc>,
+}
+
+// Everything held about a customer, answering an access request
+#[derive(Serialize)]
+pub struct PersonalDataExport<'a> {
+  pub exported_at: DateTime<Utc>,
+  pub account: &'a Account,
+  pub merged_accounts: &'a [Account], // Tombstones of the accounts merged into it
+}
+
+// Audit record of an account merge
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AccountMerge {
+  pub merge_id: Uuid,
+  pub from_account_id: Uuid,
+  pub into_account_id: Uuid,
+  pub balance_points: i32, // Balance moved over
+  pub transaction_count: usize,
+  pub card_ids: Vec<String>,
+  pub merged_by: u32,
+  pub merged_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdjustmentStatus {
+  Pending,
+  Approved { transaction_id: Uuid },
+  Rejected,
+  Expired, // Not decided in time
+}
+
+// Adjustment above the approval threshold,
+// applied only when a second user approves it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AdjustmentRequest {
+  pub request_id: Uuid,
+  pub account_id: Uuid,
+  pub amount: i32,
+  pub reason: AdjustmentReason,
+  pub notes: String,
+  pub status: AdjustmentStatus,
+  pub requested_by: u32,
+  pub requested_at: DateTime<Utc>,
+  pub decided_by: Option<u32>,
+  pub decided_at: Option<DateTime<Utc>>,
+  pub expires_at: DateTime<Utc>,
+}
+
+pub enum AdjustmentOutcome {
+  Applied(Transaction),
+  Pending(AdjustmentRequest),
+}
+
+// Reason code of a manual point adjustment
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AdjustmentReason {
+  Correction,   // Fix of a wrong balance
+  Goodwill,     // Gesture toward the customer
+  Compensation, // Missing points of a purchase
+  Fraud,        // Points taken back after abuse
+  Other,        // Notes are mandatory
+  Forfeit,      // Points lost on closing the account
+  PayOut,       // Points paid out on closing the account
+}
+
+impl AdjustmentReason {
+  const ALL: [AdjustmentReason; 7] = [
+    AdjustmentReason::Correction,
+    AdjustmentReason::Goodwill,
+    AdjustmentReason::Compensation,
+    AdjustmentReason::Fraud,
+    AdjustmentReason::Other,
+    AdjustmentReason::Forfeit,
+    AdjustmentReason::PayOut,
+  ];
+}
+
+impl std::str::FromStr for AdjustmentReason {
+  type Err = String;
+  fn from_str(str: &str) -> Result<Self, Self::Err> {
+    Self::ALL
+      .iter()
+      .find(|r| r.to_string().eq_ignore_ascii_case(str))
+      .cloned()
+      .ok_or(format!(
+        "Nem megfelelő korrekciós ok! Lehetséges értékek: {}",
+        Self::ALL
+          .iter()
+          .map(|r| r.to_string())
+          .collect::<Vec<String>>()
+          .join(", ")
+      ))
+  }
+}
+
+impl std::fmt::Display for AdjustmentReason {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}", self)
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum LevelChangeReason {
+  // Yearly turnover reached a higher tier
+  Turnover { year: i32, turnover: i32 },
+  // Yearly requalification based on the given year's turnover
+  Requalification { year: i32, turnover: i32 },
+  // Turnover dropped by refunding the given purchase
+  Refund { purchase_id: Uuid },
+  // Set by staff
+  Manual,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LevelChange {
+  pub from: LoyaltyLevel,
+  pub to: LoyaltyLevel,
+  pub reason: LevelChangeReason,
+  pub created_at: DateTime<Utc>,
+}
+
+// Downgrade decided by requalification,
+// applied after the grace period
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PendingDowngrade {
+  pub loyalty_level: LoyaltyLevel,
+  pub effective_at: DateTime<Utc>,
+  pub reason: LevelChangeReason,
+}
+
+// Points earned together, expiring together
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(from = "StoredPointLot")]
+pub struct PointLot {
+  pub lot_id: Uuid, // ID of the transaction the points were earned by
+  pub amount: Points,
+  pub usages: Vec<LotUsage>, // Burns and expiry taken from this lot
+  pub created_at: DateTime<Utc>,
+  pub available_at: DateTime<Utc>, // Pending until then
+  pub expires_at: DateTime<Utc>,
+}
+
+impl PointLot {
+  pub fn remaining(&self) -> Points {
+    // Usages never exceed the lot amount
+    Points::checked_sum(self.usages.iter().map(|u| u.amount))
+      .and_then(|used| self.amount.checked_sub(used))
+      .unwrap_or_default()
+  }
+}
+
+// Point lot as stored, lots stored before pending points
+// have no availability and were available when created
+#[derive(Deserialize)]
+struct StoredPointLot {
+  lot_id: Uuid,
+  amount: Points,
+  usages: Vec<LotUsage>,
+  created_at: DateTime<Utc>,
+  #[serde(default)]
+  available_at: Option<DateTime<Utc>>,
+  expires_at: DateTime<Utc>,
+}
+
+impl From<StoredPointLot> for PointLot {
+  fn from(lot: StoredPointLot) -> Self {
+    Self {
+      lot_id: lot.lot_id,
+      amount: lot.amount,
+      usages: lot.usages,
+      created_at: lot.created_at,
+      available_at: lot.available_at.unwrap_or(lot.created_at),
+      expires_at: lot.expires_at,
+    }
+  }
+}
+
+// Lots a debit can be taken from
+#[derive(Clone, Copy, PartialEq)]
+enum LotSource {
+  Available,      // Spendable points only
+  AvailableFirst, // Pending points too, once the available ones are used up
+  Pending,        // Pending points only
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LotUsage {
+  pub transaction_id: Uuid,
+  pub amount: Points,
+}
+
+// Points on hold during checkout
+// Lowers the spendable balance, but not the ledger balance
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Reservation {
+  pub reservation_id: Uuid,
+  pub purchase_id: Uuid,
+  pub amount: Points,
+  pub created_by: u32,
+  pub created_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+}
+
+pub struct PurchaseInfo {
+  pub purchase_id: Uuid,
+  pub payable_total_gross: Money,
+  pub created_by: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum TransactionKind {
+  // Earns stored before exact rates have the f32 discount,
+  // rounded half up
+  Earn {
+    total_payable_amount: Money,
+    #[serde(alias = "discount")]
+    rate: Rate,
+    #[serde(default)]
+    rounding: RoundingPolicy, // Rounding applied to the earned points
+    #[serde(default)]
+    campaign_id: Option<Uuid>, // Campaign applied to the earned points
+  },
+  Burn,
+  Expire {
+    lot_id: Uuid,
+  },
+  Refund {
+    earn_transaction_id: Uuid,
+    refund_amount: Money,
+    rounding: RoundingPolicy, // Same as the one of the earn
+  },
+  BurnCancel {
+    burn_transaction_ids: Vec<Uuid>,
+  },
+  // Birthday reward given for the birthday of the given year
+  Birthday {
+    year: i32,
+  },
+  // Birthday reward taken back by a refund of its purchase
+  BirthdayRefund {
+    birthday_transaction_id: Uuid,
+  },
+  // Points moved to another account
+  TransferOut {
+    transfer_id: Uuid,
+    to_account_id: Uuid,
+    pooled: bool, // Moved within the household to cover a burn
+  },
+  // Points moved from another account
+  TransferIn {
+    transfer_id: Uuid,
+    from_account_id: Uuid,
+  },
+  // Manual correction by staff, adds or takes the amount
+  Adjustment {
+    reason: AdjustmentReason,
+    notes: String,
+    is_credit: bool,
+  },
+}
+
+impl Default for TransactionKind {
+  fn default() -> Self {
+    Self::Burn
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Transaction {
+  pub transaction_id: Uuid,
+  pub account_id: Uuid,
+  pub purchase_id: Uuid,
+  pub transaction_kind: TransactionKind,
+  pub amount: Points,
+  pub crated_by: u32,
+  pub created_at: DateTime<Utc>,
+}
+
+impl Transaction {
+  pub fn new(
+    purchase_id: Uuid,
+    account_id: Uuid,
+    transaction_kind: TransactionKind,
+    amount: Points,
+    crated_by: u32,
+  ) -> Self {
+    Self {
+      transaction_id: Uuid::new_v4(),
+      purchase_id,
+      account_id,
+      transaction_kind,
+      amount,
+      crated_by,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+impl Default for Transaction {
+  fn default() -> Self {
+    Self {
+      transaction_id: Uuid::default(),
+      purchase_id: Uuid::default(),
+      account_id: Uuid::default(),
+      transaction_kind: TransactionKind::default(),
+      amount: Points::zero(),
+      crated_by: 0,
+      created_at: Utc::now(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PurchaseSummary {
+  pub balance_opening: i32,
+  pub burned_points: i32,
+  pub earned_points: i32,
+  pub balance_closing: i32,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct Transaction {
-  pub transaction_id: Uuid,
-  pub account_id: Uuid,
-  pub purchase_id: Uuid,
-  pub transaction_kind: TransactionKind,
-  pub amount: i32,
-  pub crated_by: u32,
-  pub created_at: DateTime<Utc>,
-}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ClosedPurchase {
+  pub purchase_id: Uuid,
+  pub payable_total_gross: Money,
+  pub summary: PurchaseSummary,
+  pub closed_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::{BirthdayConfig, Tier};
+  use chrono::{Duration, TimeZone};
+
+  // Account as stored by an earlier version, without the given fields
+  fn reload_without(account: &Account, fields: &[&str]) -> Account {
+    let mut stored = serde_json::to_value(account).unwrap();
+    for field in fields {
+      stored.as_object_mut().unwrap().remove(*field);
+    }
+    serde_json::from_value(stored).unwrap()
+  }
+
+  #[test]
+  fn test_jump() {
+    let config = LoyaltyConfig::default();
+    // Create new account with L1
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    // Add 20_000 gross pruchase
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(20_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    // Should be L1
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L1"));
+    // Add 20_000 gross pruchase
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(20_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    // Should be L1
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L1"));
+    // Add 20_000 gross pruchase
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(20_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    // Should be L2
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L2"));
+  }
+
+  #[test]
+  fn test_tier_table() {
+    let config = LoyaltyConfig {
+      tiers: vec![
+        Tier::new("GOLD", "Gold", 100_000, 500),
+        Tier::new("BRONZE", "Bronze", 0, 100),
+        Tier::new("SILVER", "Silver", 30_000, 300),
+      ],
+      ..LoyaltyConfig::default()
+    }
+    .validate()
+    .unwrap();
+    assert_eq!(config.base_level(), LoyaltyLevel::new("BRONZE"));
+    assert_eq!(
+      LoyaltyLevel::from_str("silver", &config).unwrap(),
+      LoyaltyLevel::new("SILVER")
+    );
+    assert!(LoyaltyLevel::from_str("L2", &config).is_err());
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    // 1% of 40_000 with Bronze, then jump straight to Silver
+    let summary = account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(40_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    assert_eq!(summary.earned_points, 400);
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("SILVER"));
+    // Jump over to Gold
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(60_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("GOLD"));
+  }
+
+  #[test]
+  fn test_requalification() {
+    let mut config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(60_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L2"));
+    let year = Utc::today().year();
+    // New and already stored accounts are first requalified next year
+    assert!(!account.is_requalification_due(&config, Utc::now()));
+    let stored = reload_without(
+      &account,
+      &["level_changes", "last_requalification", "pending_downgrade"],
+    );
+    assert_eq!(stored.last_requalification, Some(year));
+    assert!(stored.level_changes.is_empty());
+    // Next year: last year's turnover keeps L2
+    let now = config.requalification.date_of_year(year + 1);
+    assert!(account.is_requalification_due(&config, now));
+    account.requalify(&config, now);
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L2"));
+    assert!(!account.is_requalification_due(&config, now));
+    // Year after: no turnover last year, downgrade after grace period
+    config.requalification.grace_period_days = 30;
+    let now = config.requalification.date_of_year(year + 2);
+    account.requalify(&config, now);
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L2"));
+    assert!(account.pending_downgrade.is_some());
+    let now = now + Duration::days(31);
+    assert!(account.is_requalification_due(&config, now));
+    account.requalify(&config, now);
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L1"));
+    assert_eq!(
+      account.level_changes.last().unwrap().reason,
+      LevelChangeReason::Requalification {
+        year: year + 1,
+        turnover: 0
+      }
+    );
+  }
+
+  #[test]
+  fn test_turnover_by_year() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let year = Utc::today().year();
+    account
+      .gross_turnover_by_year
+      .insert(year - 1, Money::new(70_000).unwrap());
+    for _ in 0..2 {
+      account
+        .close_purchase(
+          PurchaseInfo {
+            purchase_id: Uuid::new_v4(),
+            payable_total_gross: Money::new(10_000).unwrap(),
+            created_by: 0,
+          },
+          &config,
+          &[],
+          0,
+        )
+        .unwrap();
+    }
+    assert_eq!(account.get_yearly_gross_turnover(), 20_000);
+    assert_eq!(account.get_gross_turnover_of_year(year - 1), 70_000);
+    assert_eq!(account.get_gross_turnover_of_year(year - 2), 0);
+    // Last year's turnover does not upgrade during the year
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L1"));
+    // Stored before per-year turnover: rebuilt from the purchases
+    let mut stored = reload_without(&account, &["version", "gross_turnover_by_year"]);
+    assert!(stored.needs_upgrade());
+    stored.upgrade(&config, Utc::now()).unwrap();
+    assert!(!stored.needs_upgrade());
+    assert_eq!(stored.get_yearly_gross_turnover(), 20_000);
+    // Turnover moved out (carry over) is not rebuilt
+    let mut drained = account.clone();
+    drained.gross_turnover_by_year.clear();
+    assert!(!drained.needs_upgrade());
+  }
+
+  #[test]
+  fn test_point_expiry() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    for total in &[10_000, 5_000] {
+      account
+        .close_purchase(
+          PurchaseInfo {
+            purchase_id: Uuid::new_v4(),
+            payable_total_gross: Money::new(*total).unwrap(),
+            created_by: 0,
+          },
+          &config,
+          &[],
+          0,
+        )
+        .unwrap();
+    }
+    assert_eq!(account.point_lots.len(), 2);
+    assert_eq!(
+      account.point_lots[0].expires_at,
+      Utc.ymd(Utc::today().year() + 3, 1, 1).and_hms(0, 0, 0)
+    );
+    // Burn takes the oldest lot first
+    account
+      .burn_points(Uuid::new_v4(), Points::new(250).unwrap(), 0)
+      .unwrap();
+    assert_eq!(account.point_lots[0].remaining().value(), 0);
+    assert_eq!(account.point_lots[1].remaining().value(), 50);
+    // Stored before point lots: the balance becomes a lot on upgrade
+    let now = Utc::now();
+    let mut stored = reload_without(&account, &["version", "point_lots"]);
+    assert!(stored.point_lots.is_empty());
+    assert!(stored.needs_upgrade());
+    stored.upgrade(&config, now).unwrap();
+    assert_eq!(stored.point_lots.len(), 1);
+    assert_eq!(stored.point_lots[0].remaining().value(), 50);
+    assert_eq!(stored.point_lots[0].expires_at, config.point_expiry_of(now));
+    // and expires like any other lot
+    let lapsed = config.point_expiry_of(now);
+    let expired = stored.expire_points(lapsed).unwrap();
+    assert_eq!(expired[0].amount.value(), 50);
+    assert_eq!(stored.get_balance(), 0);
+    // Let the second lot lapse
+    account.point_lots[1].expires_at = now;
+    assert!(account.has_expired_points(now));
+    let expired = account.expire_points(now).unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].amount.value(), 50);
+    assert_eq!(account.get_balance(), 0);
+    assert_eq!(account.point_lots.len(), 1);
+    assert!(!account.has_expired_points(now));
+  }
+
+  #[test]
+  fn test_refund() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let purchase_id = Uuid::new_v4();
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id,
+          payable_total_gross: Money::new(60_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L2"));
+    assert_eq!(account.get_balance(), 1200);
+    // Partial refund takes back proportional points
+    let refund = account
+      .refund_purchase(purchase_id, Money::new(15_000).unwrap(), &config, 0)
+      .unwrap();
+    assert_eq!(refund.amount.value(), 300);
+    assert_eq!(account.get_balance(), 900);
+    assert_eq!(account.get_yearly_gross_turnover(), 45_000);
+    assert_eq!(account.point_lots[0].remaining().value(), 900);
+    assert_eq!(account.loyalty_level, LoyaltyLevel::new("L1"));
+    // Cannot refund more than what is left
+    assert!(account
+      .refund_purchase(purchase_id, Money::new(45_001).unwrap(), &config, 0)
+      .is_err());
+    // Unknown purchase
+    assert!(account
+      .refund_purchase(Uuid::new_v4(), Money::new(1).unwrap(), &config, 0)
+      .is_err());
+    // Last refund takes back the rest
+    account
+      .refund_purchase(purchase_id, Money::new(45_000).unwrap(), &config, 0)
+      .unwrap();
+    assert_eq!(account.get_balance(), 0);
+    assert_eq!(account.get_yearly_gross_turnover(), 0);
+    // Earn stored with the former f32 discount
+    let stored: TransactionKind =
+      serde_json::from_str(r#"{"Earn":{"total_payable_amount":60000,"discount":0.02}}"#).unwrap();
+    assert!(matches!(
+      stored,
+      TransactionKind::Earn { rate, rounding: RoundingPolicy::HalfUp, campaign_id: None, .. }
+        if rate.basis_points() == 200
+    ));
+  }
+
+  #[test]
+  fn test_cancel_burn() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(10_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    let purchase_id = Uuid::new_v4();
+    account
+      .burn_points(purchase_id, Points::new(150).unwrap(), 0)
+      .unwrap();
+    assert_eq!(account.get_balance(), 50);
+    // Not while the account is suspended
+    account
+      .set_status(AccountStatus::Suspended, "Check".to_string(), &config, 0)
+      .unwrap();
+    assert!(account.cancel_burn(purchase_id, 0).is_err());
+    account
+      .set_status(AccountStatus::Active, "Checked".to_string(), &config, 0)
+      .unwrap();
+    let cancel = account.cancel_burn(purchase_id, 0).unwrap();
+    assert_eq!(cancel.amount.value(), 150);
+    assert_eq!(account.get_balance(), 200);
+    assert_eq!(account.point_lots[0].remaining().value(), 200);
+    assert_eq!(account.get_burned_points(purchase_id), 0);
+    // Nothing left to cancel
+    assert!(account.cancel_burn(purchase_id, 0).is_err());
+    // Burn again, then close the purchase
+    account
+      .burn_points(purchase_id, Points::new(100).unwrap(), 0)
+      .unwrap();
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id,
+          payable_total_gross: Money::new(1_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    assert!(account.cancel_burn(purchase_id, 0).is_err());
+  }
+
+  #[test]
+  fn test_reservation() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(10_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    let purchase_id = Uuid::new_v4();
+    account
+      .reserve_points(purchase_id, Points::new(150).unwrap(), &config, 0)
+      .unwrap();
+    assert_eq!(account.get_balance(), 200);
+    assert_eq!(account.get_spendable_balance(), 50);
+    // Same points cannot be spent at another till
+    assert!(account
+      .burn_points(Uuid::new_v4(), Points::new(100).unwrap(), 0)
+      .is_err());
+    assert!(account
+      .reserve_points(Uuid::new_v4(), Points::new(100).unwrap(), &config, 0)
+      .is_err());
+    // Commit on close
+    let summary = account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id,
+          payable_total_gross: Money::new(0).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    assert_eq!(summary.burned_points, 150);
+    assert_eq!(account.get_balance(), 50);
+    assert!(account.reservations.is_empty());
+    // Release after timeout
+    account
+      .reserve_points(Uuid::new_v4(), Points::new(50).unwrap(), &config, 0)
+      .unwrap();
+    let now = Utc::now() + config.reservation_timeout();
+    assert!(account.has_expired_reservations(now));
+    assert_eq!(account.release_expired_reservations(now).len(), 1);
+    assert_eq!(account.get_spendable_balance(), 50);
+    // Timed out, but not released yet: still committed on close
+    let close = |purchase_id| PurchaseInfo {
+      purchase_id,
+      payable_total_gross: Money::new(0).unwrap(),
+      created_by: 0,
+    };
+    let purchase_id = Uuid::new_v4();
+    account
+      .reserve_points(purchase_id, Points::new(30).unwrap(), &config, 0)
+      .unwrap();
+    account.reservations[0].expires_at = Utc::now();
+    let summary = account
+      .close_purchase(close(purchase_id), &config, &[], 0)
+      .unwrap();
+    assert_eq!(summary.burned_points, 30);
+    // Points spent elsewhere after the timeout, close is rejected
+    let purchase_id = Uuid::new_v4();
+    account
+      .reserve_points(purchase_id, Points::new(20).unwrap(), &config, 0)
+      .unwrap();
+    account.reservations[0].expires_at = Utc::now();
+    account
+      .burn_points(Uuid::new_v4(), Points::new(20).unwrap(), 0)
+      .unwrap();
+    assert!(account
+      .close_purchase(close(purchase_id), &config, &[], 0)
+      .is_err());
+  }
 
-impl Transaction {
-  pub fn new(
-    purchase_id: Uuid,
-    account_id: Uuid,
-    transaction_kind: TransactionKind,
-    amount: i32,
-    crated_by: u32,
-  ) -> Self {
-    Self {
-      transaction_id: Uuid::new_v4(),
+  #[test]
+  fn test_idempotent_purchase() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let info = |purchase_id, payable_total_gross| PurchaseInfo {
       purchase_id,
-      account_id,
-      transaction_kind,
-      amount,
-      crated_by,
-      created_at: Utc::now(),
-    }
+      payable_total_gross: Money::new(payable_total_gross).unwrap(),
+      created_by: 0,
+    };
+    account
+      .close_purchase(info(Uuid::new_v4(), 10_000), &config, &[], 0)
+      .unwrap();
+    let purchase_id = Uuid::new_v4();
+    assert!(account.burn_points(purchase_id, Points::zero(), 0).is_err());
+    let burn = account
+      .burn_points(purchase_id, Points::new(100).unwrap(), 0)
+      .unwrap();
+    // Retry returns the same burn
+    let retry = account
+      .burn_points(purchase_id, Points::new(100).unwrap(), 0)
+      .unwrap();
+    assert_eq!(burn.transaction_id, retry.transaction_id);
+    assert_eq!(account.get_balance(), 100);
+    // Conflicting burn
+    assert!(account
+      .burn_points(purchase_id, Points::new(50).unwrap(), 0)
+      .is_err());
+    let summary = account
+      .close_purchase(info(purchase_id, 5_000), &config, &[], 0)
+      .unwrap();
+    // Retry returns the original summary, no points earned twice
+    let retry = account
+      .close_purchase(info(purchase_id, 5_000), &config, &[], 0)
+      .unwrap();
+    assert_eq!(summary, retry);
+    assert_eq!(account.get_balance(), 200);
+    // Conflicting close
+    assert!(account
+      .close_purchase(info(purchase_id, 6_000), &config, &[], 0)
+      .is_err());
+    // No burn after close
+    assert!(account
+      .burn_points(purchase_id, Points::new(100).unwrap(), 0)
+      .is_err());
+    // Stored before closed purchases were kept, still no earning twice
+    let mut stored = reload_without(&account, &["closed_purchases"]);
+    assert!(stored
+      .close_purchase(info(purchase_id, 5_000), &config, &[], 0)
+      .is_err());
   }
-}
 
-impl Default for Transaction {
-  fn default() -> Self {
-    Self {
-      transaction_id: Uuid::default(),
-      purchase_id: Uuid::default(),
-      account_id: Uuid::default(),
-      transaction_kind: TransactionKind::default(),
-      amount: 0,
-      crated_by: 0,
-      created_at: Utc::now(),
+  #[test]
+  fn test_campaign() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let today = Utc::today().naive_utc();
+    let campaigns = vec![Campaign::new(
+      "Double points".to_string(),
+      today,
+      today,
+      Vec::new(),
+      vec![config.base_level()],
+      campaign::CampaignReward::Multiplier { percentage: 200 },
+      0,
+    )
+    .unwrap()];
+    let summary = account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(10_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &campaigns,
+        0,
+      )
+      .unwrap();
+    assert_eq!(summary.earned_points, 400);
+    match account.transactions[0].transaction_kind {
+      TransactionKind::Earn { campaign_id, .. } => {
+        assert_eq!(campaign_id, Some(campaigns[0].campaign_id))
+      }
+      _ => panic!("Earn transaction expected"),
     }
   }
-}
 
-pub struct PurchaseSummary {
-  pub balance_opening: i32,
-  pub burned_points: i32,
-  pub earned_points: i32,
-  pub balance_closing: i32,
-}
+  #[test]
+  fn test_adjustment() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    account
+      .adjust_points(300, AdjustmentReason::Goodwill, "".to_string(), &config, 1)
+      .unwrap();
+    assert_eq!(account.get_balance(), 300);
+    assert_eq!(account.point_lots[0].remaining().value(), 300);
+    let adjustment = account
+      .adjust_points(
+        -100,
+        AdjustmentReason::Correction,
+        "Double entry".to_string(),
+        &config,
+        1,
+      )
+      .unwrap();
+    assert_eq!(adjustment.amount.value(), 100);
+    assert_eq!(account.get_balance(), 200);
+    assert_eq!(account.point_lots[0].remaining().value(), 200);
+    // Notes are mandatory for other reasons
+    assert!(account
+      .adjust_points(10, AdjustmentReason::Other, " ".to_string(), &config, 1)
+      .is_err());
+    assert!(account
+      .adjust_points(0, AdjustmentReason::Correction, "".to_string(), &config, 1)
+      .is_err());
+    assert_eq!(
+      "fraud".parse::<AdjustmentReason>().unwrap(),
+      AdjustmentReason::Fraud
+    );
+    assert!("".parse::<AdjustmentReason>().is_err());
+  }
 
-#[cfg(test)]
-mod tests {
-  use super::*;
   #[test]
-  fn test_jump() {
-    // Create new account with L1
-    let mut account = Account::new(0, Utc::today().naive_local(), 0);
-    // Add 20_000 gross pruchase
+  fn test_adjustment_approval() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let threshold = config.adjustment_approval.threshold;
+    // Large adjustment waits for approval
+    let request = match account
+      .request_adjustment(
+        threshold + 1,
+        AdjustmentReason::Goodwill,
+        "".to_string(),
+        &config,
+        1,
+      )
+      .unwrap()
+    {
+      AdjustmentOutcome::Pending(request) => request,
+      AdjustmentOutcome::Applied(_) => panic!("Pending request expected"),
+    };
+    assert_eq!(account.get_balance(), 0);
+    // Maker cannot approve own request
+    assert!(account
+      .approve_adjustment(request.request_id, 1, &config)
+      .is_err());
+    let transaction = account
+      .approve_adjustment(request.request_id, 2, &config)
+      .unwrap();
+    assert_eq!(account.get_balance(), threshold + 1);
+    let request = &account.adjustment_requests[0];
+    assert_eq!(
+      request.status,
+      AdjustmentStatus::Approved {
+        transaction_id: transaction.transaction_id
+      }
+    );
+    assert_eq!((request.requested_by, request.decided_by), (1, Some(2)));
+    // Already decided
+    assert!(account.reject_adjustment(request.request_id, 2).is_err());
+    // Small adjustment applies straight away
+    assert!(matches!(
+      account
+        .request_adjustment(
+          -threshold,
+          AdjustmentReason::Correction,
+          "".to_string(),
+          &config,
+          1
+        )
+        .unwrap(),
+      AdjustmentOutcome::Applied(_)
+    ));
+    assert_eq!(account.get_balance(), 1);
+    // Undecided request expires
     account
+      .request_adjustment(
+        threshold + 1,
+        AdjustmentReason::Goodwill,
+        "".to_string(),
+        &config,
+        1,
+      )
+      .unwrap();
+    let now = Utc::now() + config.adjustment_approval.timeout();
+    assert!(account.has_expired_adjustment_requests(now));
+    assert_eq!(account.expire_adjustment_requests(now).len(), 1);
+    assert_eq!(
+      account.adjustment_requests[1].status,
+      AdjustmentStatus::Expired
+    );
+  }
+
+  #[test]
+  fn test_transfer() {
+    let mut config = LoyaltyConfig::default();
+    config.transfer.daily_limit = 150;
+    let mut from = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let mut to = Account::new(1, Utc::today().naive_local(), config.base_level(), 0);
+    from
       .close_purchase(
         PurchaseInfo {
           purchase_id: Uuid::new_v4(),
-          payable_total_gross: 20_000,
+          payable_total_gross: Money::new(10_000).unwrap(),
           created_by: 0,
         },
+        &config,
+        &[],
         0,
       )
       .unwrap();
-    // Should be L1
-    assert_eq!(account.loyalty_level, LoyaltyLevel::L1);
-    // Add 20_000 gross pruchase
+    let transfer =
+      transfer_points(&mut from, &mut to, Points::new(100).unwrap(), &config, 0).unwrap();
+    assert_eq!(from.get_balance(), 100);
+    assert_eq!(to.get_balance(), 100);
+    assert_eq!(transfer.credit.amount, transfer.debit.amount);
+    // Transferred points keep their expiry
+    assert_eq!(to.point_lots[0].expires_at, from.point_lots[0].expires_at);
+    // Over the daily limit, nothing changes
+    assert!(transfer_points(&mut from, &mut to, Points::new(60).unwrap(), &config, 0).is_err());
+    assert_eq!(from.get_balance(), 100);
+    assert_eq!(to.transactions.len(), 1);
+    // Not enough points
+    assert!(transfer_points(&mut to, &mut from, Points::new(101).unwrap(), &config, 0).is_err());
+  }
+
+  #[test]
+  fn test_birthday() {
+    let mut config = LoyaltyConfig::default();
+    config.birthday.reward = Some(BirthdayReward::Bonus { points: 500 });
+    let today = Utc::today().naive_utc();
+    let mut account = Account::new(0, today - Duration::days(3), config.base_level(), 0);
+    let info = |payable_total_gross| PurchaseInfo {
+      purchase_id: Uuid::new_v4(),
+      payable_total_gross: Money::new(payable_total_gross).unwrap(),
+      created_by: 0,
+    };
+    // Bonus on the first purchase of the birthday week only
+    let summary = account
+      .close_purchase(info(10_000), &config, &[], 0)
+      .unwrap();
+    assert_eq!(summary.earned_points, 700);
+    account
+      .close_purchase(info(10_000), &config, &[], 0)
+      .unwrap();
+    assert_eq!(account.get_balance(), 900);
+    assert_eq!(account.point_lots.len(), 3);
+    // Double points instead, already given this year
+    config.birthday.reward = Some(BirthdayReward::Multiplier { percentage: 200 });
+    let summary = account
+      .close_purchase(info(10_000), &config, &[], 0)
+      .unwrap();
+    assert_eq!(summary.earned_points, 200);
+    // Out of the window
+    let mut account = Account::new(0, today - Duration::days(7), config.base_level(), 0);
+    let summary = account
+      .close_purchase(info(10_000), &config, &[], 0)
+      .unwrap();
+    assert_eq!(summary.earned_points, 200);
+    // Refund takes the reward back in proportion, in full on the last one
+    config.birthday.reward = Some(BirthdayReward::Bonus { points: 500 });
+    let mut account = Account::new(0, today, config.base_level(), 0);
+    let purchase = info(10_000);
+    let purchase_id = purchase.purchase_id;
+    account.close_purchase(purchase, &config, &[], 0).unwrap();
+    account
+      .refund_purchase(purchase_id, Money::new(5_000).unwrap(), &config, 0)
+      .unwrap();
+    assert_eq!(account.get_balance(), 350);
+    account
+      .refund_purchase(purchase_id, Money::new(5_000).unwrap(), &config, 0)
+      .unwrap();
+    assert_eq!(account.get_balance(), 0);
+    assert!(account.point_lots.iter().all(|l| l.remaining().is_zero()));
+    // The reward taken back is given again
+    let summary = account
+      .close_purchase(info(10_000), &config, &[], 0)
+      .unwrap();
+    assert_eq!(summary.earned_points, 700);
+    // Leap day birthday
+    let birthdate = NaiveDate::from_ymd(2000, 2, 29);
+    assert_eq!(
+      BirthdayConfig::birthday_of_year(birthdate, 2021),
+      NaiveDate::from_ymd(2021, 2, 28)
+    );
+    assert_eq!(
+      config
+        .birthday
+        .celebrated_year(birthdate, NaiveDate::from_ymd(2024, 2, 28)),
+      None
+    );
+    assert_eq!(
+      config
+        .birthday
+        .celebrated_year(birthdate, NaiveDate::from_ymd(2021, 3, 1)),
+      Some(2021)
+    );
+  }
+  #[test]
+  fn test_cards() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    assert!(account.set_card("1234".to_string()).is_err());
+    account.set_card("79927398713".to_string()).unwrap();
+    assert!(account.set_card("79927398713".to_string()).is_err());
+    // Blocked card can be activated again
+    account
+      .change_card_state("79927398713", CardState::Blocked, "Suspicious".to_string())
+      .unwrap();
+    assert!(account.get_active_card().is_none());
+    assert!(account
+      .get_card("79927398713")
+      .unwrap()
+      .retired_at
+      .is_some());
+    account
+      .change_card_state("79927398713", CardState::Active, "Checked".to_string())
+      .unwrap();
+    assert!(account
+      .get_card("79927398713")
+      .unwrap()
+      .retired_at
+      .is_none());
+    // Wrong new card keeps the old one active
+    assert!(account
+      .replace_card("79927398713", "1234".to_string(), "Worn".to_string())
+      .is_err());
+    assert_eq!(
+      account.get_active_card().unwrap().card_id,
+      "79927398713".to_string()
+    );
+    account
+      .replace_card(
+        "79927398713",
+        "4111111111111111".to_string(),
+        "Worn".to_string(),
+      )
+      .unwrap();
+    assert_eq!(account.cards.len(), 2);
+    assert_eq!(
+      account.get_card("79927398713").unwrap().state,
+      CardState::Replaced
+    );
+    assert_eq!(
+      account.get_active_card().unwrap().card_id,
+      "4111111111111111".to_string()
+    );
+    assert_eq!(
+      account.get_card("79927398713").unwrap().reason,
+      "Worn".to_string()
+    );
+    // Replaced card is final
+    assert!(account
+      .change_card_state("79927398713", CardState::Active, "".to_string())
+      .is_err());
+    assert_eq!("lost".parse::<CardState>().unwrap(), CardState::Lost);
+    // New card retires the one in use
+    account.set_card("5105105105105100".to_string()).unwrap();
+    assert_eq!(
+      account.get_card("4111111111111111").unwrap().state,
+      CardState::Replaced
+    );
+    assert_eq!(
+      account
+        .cards
+        .iter()
+        .filter(|c| c.state == CardState::Active)
+        .count(),
+      1
+    );
+    // New card retires a blocked one too, it cannot be unblocked beside it
     account
+      .change_card_state("5105105105105100", CardState::Blocked, "".to_string())
+      .unwrap();
+    account.set_card("4242424242424242".to_string()).unwrap();
+    assert!(account
+      .change_card_state("5105105105105100", CardState::Active, "".to_string())
+      .is_err());
+    assert_eq!(
+      account.get_active_card().unwrap().card_id,
+      "4242424242424242".to_string()
+    );
+    // Single card of an earlier version is moved into cards
+    let mut stored = serde_json::to_value(Account::new(
+      1,
+      Utc::today().naive_local(),
+      config.base_level(),
+      0,
+    ))
+    .unwrap();
+    stored["card_id"] = serde_json::json!("79927398713");
+    stored.as_object_mut().unwrap().remove("cards");
+    let mut legacy: Account = serde_json::from_value(stored).unwrap();
+    assert!(legacy.needs_upgrade());
+    legacy.upgrade(&config, Utc::now()).unwrap();
+    assert!(!legacy.needs_upgrade());
+    assert_eq!(
+      legacy.get_active_card().unwrap().card_id,
+      "79927398713".to_string()
+    );
+    assert!(serde_json::to_value(&legacy).unwrap()["card_id"].is_null());
+  }
+  #[test]
+  fn test_anonymous() {
+    let config = LoyaltyConfig {
+      birthday: BirthdayConfig {
+        reward: Some(BirthdayReward::Bonus { points: 100 }),
+        ..BirthdayConfig::default()
+      },
+      ..LoyaltyConfig::default()
+    };
+    let mut account = Account::new_anonymous(config.base_level(), 0);
+    assert!(account.is_anonymous());
+    // Collects points without customer data, no birthday reward
+    let summary = account
       .close_purchase(
         PurchaseInfo {
           purchase_id: Uuid::new_v4(),
-          payable_total_gross: 20_000,
+          payable_total_gross: Money::new(10_000).unwrap(),
           created_by: 0,
         },
+        &config,
+        &[],
         0,
       )
       .unwrap();
-    // Should be L1
-    assert_eq!(account.loyalty_level, LoyaltyLevel::L1);
-    // Add 20_000 gross pruchase
+    assert_eq!(summary.earned_points, 200);
+    let today = Utc::today().naive_local();
+    account.link_customer(1, today).unwrap();
+    assert_eq!(account.customer_id, Some(1));
+    assert_eq!(account.customer_birthdate, Some(today));
+    assert_eq!(account.get_balance(), 200);
+    assert!(account.link_customer(2, today).is_err());
+    // Customer data stored before anonymous accounts loads as set
+    let mut stored = serde_json::to_value(&account).unwrap();
+    stored["customer_id"] = serde_json::json!(3);
+    stored["customer_birthdate"] = serde_json::json!("1990-05-01");
+    let stored: Account = serde_json::from_value(stored).unwrap();
+    assert_eq!(stored.customer_id, Some(3));
+    assert_eq!(
+      stored.customer_birthdate,
+      Some(NaiveDate::from_ymd(1990, 5, 1))
+    );
+    assert!(!stored.is_anonymous());
+  }
+  #[test]
+  fn test_merge() {
+    let config = LoyaltyConfig::default();
+    let today = Utc::today().naive_local();
+    let mut survivor = Account::new(1, today, config.base_level(), 0);
+    let mut merged = Account::new_anonymous(config.base_level(), 0);
+    merged.set_card("79927398713".to_string()).unwrap();
+    for account in [&mut survivor, &mut merged] {
+      account
+        .close_purchase(
+          PurchaseInfo {
+            purchase_id: Uuid::new_v4(),
+            payable_total_gross: Money::new(30_000).unwrap(),
+            created_by: 0,
+          },
+          &config,
+          &[],
+          0,
+        )
+        .unwrap();
+    }
+    assert!(merge_accounts(&mut survivor.clone(), &mut survivor, &config, 0).is_err());
+    // Not out of a suspended account
+    merged
+      .set_status(AccountStatus::Suspended, "Check".to_string(), &config, 0)
+      .unwrap();
+    assert!(merge_accounts(&mut survivor, &mut merged, &config, 7).is_err());
+    merged
+      .set_status(AccountStatus::Active, "Checked".to_string(), &config, 0)
+      .unwrap();
+    let merge = merge_accounts(&mut survivor, &mut merged, &config, 7).unwrap();
+    assert_eq!(merge.balance_points, 600);
+    assert_eq!(merge.card_ids, vec!["79927398713".to_string()]);
+    assert_eq!(survivor.get_balance(), 1200);
+    assert_eq!(survivor.get_yearly_gross_turnover(), 60_000);
+    assert_eq!(survivor.loyalty_level, LoyaltyLevel::new("L2"));
+    assert_eq!(survivor.transactions.len(), 2);
+    assert!(survivor
+      .transactions
+      .iter()
+      .all(|t| t.account_id == survivor.account_id));
+    assert!(survivor.get_card("79927398713").is_some());
+    assert_eq!(survivor.merges.len(), 1);
+    // Tombstone
+    assert_eq!(merged.merged_into, Some(survivor.account_id));
+    assert_eq!(merged.get_balance(), 0);
+    assert!(merged.transactions.is_empty() && merged.cards.is_empty());
+    assert!(merged
+      .burn_points(Uuid::new_v4(), Points::new(1).unwrap(), 0)
+      .is_err());
+    assert!(merge_accounts(&mut survivor, &mut merged, &config, 7).is_err());
+    // Export covers the tombstone too
+    let export: serde_json::Value = serde_json::from_str(
+      &survivor
+        .export_personal_data(std::slice::from_ref(&merged))
+        .unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+      export["merged_accounts"][0]["merges"][0]["card_ids"][0],
+      "79927398713"
+    );
+    // Merged points can be burned together
+    survivor
+      .burn_points(Uuid::new_v4(), Points::new(1200).unwrap(), 0)
+      .unwrap();
+    assert_eq!(survivor.get_balance(), 0);
+    // Erasure covers the tombstone too
+    assert!(erase_tombstone(&mut survivor.clone(), 9).is_err());
+    survivor.erase_personal_data(9).unwrap();
+    erase_tombstone(&mut merged, 9).unwrap();
+    assert!(survivor.merges[0].card_ids.is_empty());
+    assert!(merged.merges[0].card_ids.is_empty());
+    assert_eq!(merged.erased_by, Some(9));
+  }
+  #[test]
+  fn test_account_status() {
+    let mut config = LoyaltyConfig::default();
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let purchase = |account: &mut Account, config: &LoyaltyConfig| {
+      account.close_purchase(
+        PurchaseInfo {
+          purchase_id: Uuid::new_v4(),
+          payable_total_gross: Money::new(10_000).unwrap(),
+          created_by: 0,
+        },
+        config,
+        &[],
+        0,
+      )
+    };
+    purchase(&mut account, &config).unwrap();
+    // Reason is mandatory
+    assert!(account
+      .set_status(AccountStatus::Suspended, "".to_string(), &config, 1)
+      .is_err());
+    account
+      .set_status(
+        AccountStatus::Suspended,
+        "Fraud check".to_string(),
+        &config,
+        1,
+      )
+      .unwrap();
+    assert!(purchase(&mut account, &config).is_err());
+    assert!(account
+      .burn_points(Uuid::new_v4(), Points::new(10).unwrap(), 0)
+      .is_err());
+    account
+      .set_status(AccountStatus::Active, "".to_string(), &config, 1)
+      .unwrap();
+    account
+      .burn_points(Uuid::new_v4(), Points::new(10).unwrap(), 0)
+      .unwrap();
+    // Remaining points are paid out on closing
+    config.closing_policy = ClosingPolicy::PayOut;
+    let payout = account
+      .set_status(
+        AccountStatus::Closed,
+        "Customer request".to_string(),
+        &config,
+        1,
+      )
+      .unwrap()
+      .unwrap();
+    assert_eq!(payout.amount.value(), 190);
+    assert!(matches!(
+      payout.transaction_kind,
+      TransactionKind::Adjustment {
+        reason: AdjustmentReason::PayOut,
+        is_credit: false,
+        ..
+      }
+    ));
+    assert_eq!(account.get_balance(), 0);
+    assert!(purchase(&mut account, &config).is_err());
+    assert!(account
+      .set_status(AccountStatus::Suspended, "Wrong".to_string(), &config, 1)
+      .is_err());
+    // Reopened account starts from zero
+    account
+      .set_status(AccountStatus::Active, "Returned".to_string(), &config, 1)
+      .unwrap();
+    purchase(&mut account, &config).unwrap();
+    assert_eq!(account.get_balance(), 200);
+    assert_eq!(account.status_changes.len(), 4);
+    // Adjustments need an active account
+    account
+      .set_status(
+        AccountStatus::Suspended,
+        "Fraud check".to_string(),
+        &config,
+        1,
+      )
+      .unwrap();
+    assert!(account
+      .adjust_points(
+        -10,
+        AdjustmentReason::Correction,
+        "".to_string(),
+        &config,
+        1
+      )
+      .is_err());
+    assert!(account
+      .request_adjustment(
+        -10,
+        AdjustmentReason::Correction,
+        "".to_string(),
+        &config,
+        1
+      )
+      .is_err());
+    account
+      .set_status(AccountStatus::Active, "".to_string(), &config, 1)
+      .unwrap();
+    // Undecided requests expire on closing, large payout waits for approval
+    config.adjustment_approval.threshold = 100;
+    account
+      .request_adjustment(150, AdjustmentReason::Goodwill, "".to_string(), &config, 1)
+      .unwrap();
+    assert!(account
+      .set_status(AccountStatus::Closed, "Moved".to_string(), &config, 1)
+      .unwrap()
+      .is_none());
+    assert_eq!(account.get_balance(), 200);
+    assert_eq!(
+      account.adjustment_requests[0].status,
+      AdjustmentStatus::Expired
+    );
+    let request = account.adjustment_requests[1].clone();
+    assert_eq!(request.amount, -200);
+    assert_eq!(request.reason, AdjustmentReason::PayOut);
+    assert!(account
+      .adjust_points(10, AdjustmentReason::Goodwill, "".to_string(), &config, 1)
+      .is_err());
+    account
+      .approve_adjustment(request.request_id, 2, &config)
+      .unwrap();
+    assert_eq!(account.get_balance(), 0);
+  }
+  #[test]
+  fn test_erasure() {
+    let config = LoyaltyConfig::default();
+    let mut account = Account::new(42, Utc::today().naive_local(), config.base_level(), 0);
+    account.set_card("79927398713".to_string()).unwrap();
+    account.join_household(Uuid::new_v4()).unwrap();
     account
       .close_purchase(
         PurchaseInfo {
           purchase_id: Uuid::new_v4(),
-          payable_total_gross: 20_000,
+          payable_total_gross: Money::new(10_000).unwrap(),
           created_by: 0,
         },
+        &config,
+        &[],
         0,
       )
       .unwrap();
-    // Should be L2
-    assert_eq!(account.loyalty_level, LoyaltyLevel::L2);
+    account
+      .adjust_points(
+        50,
+        AdjustmentReason::Goodwill,
+        "Called from +36 1 234 5678".to_string(),
+        &config,
+        1,
+      )
+      .unwrap();
+    // Export holds everything, as JSON
+    let export: serde_json::Value =
+      serde_json::from_str(&account.export_personal_data(&[]).unwrap()).unwrap();
+    assert_eq!(export["account"]["customer_id"], 42);
+    assert_eq!(export["account"]["cards"][0]["card_id"], "79927398713");
+    assert_eq!(
+      export["account"]["transactions"].as_array().unwrap().len(),
+      2
+    );
+    let card_ids = account.erase_personal_data(9).unwrap();
+    assert_eq!(card_ids, vec!["79927398713".to_string()]);
+    assert!(account.customer_id.is_none() && account.customer_birthdate.is_none());
+    assert!(account.cards.is_empty() && account.household_id.is_none());
+    assert_eq!(account.erased_by, Some(9));
+    // Transactions are kept for accounting, without free text
+    assert_eq!(account.transactions.len(), 2);
+    assert_eq!(account.get_balance(), 250);
+    assert!(matches!(
+      &account.transactions[1].transaction_kind,
+      TransactionKind::Adjustment { notes, .. } if notes.is_empty()
+    ));
+    assert!(account
+      .link_customer(43, Utc::today().naive_local())
+      .is_err());
+    assert!(account.erase_personal_data(9).is_err());
+  }
+
+  #[test]
+  fn test_pending_points() {
+    let config = LoyaltyConfig {
+      pending_days: 14,
+      ..LoyaltyConfig::default()
+    };
+    let mut account = Account::new(0, Utc::today().naive_local(), config.base_level(), 0);
+    let purchase_id = Uuid::new_v4();
+    account
+      .close_purchase(
+        PurchaseInfo {
+          purchase_id,
+          payable_total_gross: Money::new(30_000).unwrap(),
+          created_by: 0,
+        },
+        &config,
+        &[],
+        0,
+      )
+      .unwrap();
+    let now = Utc::now();
+    assert_eq!(account.get_balance(), 600);
+    assert_eq!(account.get_pending_balance(now), 600);
+    assert_eq!(account.get_available_balance(now), 0);
+    assert_eq!(account.get_spendable_balance(), 0);
+    // Pending points cannot be spent
+    assert!(account
+      .burn_points(Uuid::new_v4(), Points::new(1).unwrap(), 0)
+      .is_err());
+    // Refund takes back from the pending lot
+    account
+      .refund_purchase(purchase_id, Money::new(10_000).unwrap(), &config, 0)
+      .unwrap();
+    assert_eq!(account.get_pending_balance(now), 400);
+    // Available after the return window
+    let later = now + Duration::days(14);
+    assert_eq!(account.get_pending_balance(later), 0);
+    assert_eq!(account.get_available_balance(later), 400);
+    account.point_lots[0].available_at = now;
+    account
+      .burn_points(Uuid::new_v4(), Points::new(400).unwrap(), 0)
+      .unwrap();
+    assert_eq!(account.get_balance(), 0);
+    // Lots stored before pending points were available when created
+    let mut stored = serde_json::to_value(&account.point_lots[0]).unwrap();
+    stored.as_object_mut().unwrap().remove("available_at");
+    let lot: PointLot = serde_json::from_value(stored).unwrap();
+    assert_eq!(lot.available_at, lot.created_at);
+    // Debits take pending points once the available ones are used up
+    let purchase = |account: &mut Account, total: i32| {
+      account
+        .close_purchase(
+          PurchaseInfo {
+            purchase_id: Uuid::new_v4(),
+            payable_total_gross: Money::new(total).unwrap(),
+            created_by: 0,
+          },
+          &config,
+          &[],
+          0,
+        )
+        .unwrap();
+    };
+    purchase(&mut account, 30_000);
+    account
+      .adjust_points(50, AdjustmentReason::Goodwill, "".to_string(), &config, 1)
+      .unwrap();
+    account
+      .adjust_points(
+        -100,
+        AdjustmentReason::Correction,
+        "".to_string(),
+        &config,
+        1,
+      )
+      .unwrap();
+    let now = Utc::now();
+    assert_eq!(account.get_balance(), 550);
+    assert_eq!(account.get_pending_balance(now), 550);
+    assert_eq!(account.get_available_balance(now), 0);
+    // Pending points are carried over with their availability
+    let mut anonymous = Account::new_anonymous(config.base_level(), 0);
+    purchase(&mut anonymous, 10_000);
+    carry_over(&mut anonymous, &mut account, &config, 0).unwrap();
+    assert_eq!(anonymous.get_balance(), 0);
+    assert_eq!(account.get_balance(), 750);
+    assert_eq!(account.get_pending_balance(now), 750);
+    assert_eq!(account.get_pending_balance(now + Duration::days(15)), 0);
+    // Pending points are forfeited on closing, not paid out
+    account
+      .adjust_points(30, AdjustmentReason::Goodwill, "".to_string(), &config, 1)
+      .unwrap();
+    let config = LoyaltyConfig {
+      closing_policy: ClosingPolicy::PayOut,
+      ..config
+    };
+    let payout = account
+      .set_status(AccountStatus::Closed, "Moved".to_string(), &config, 1)
+      .unwrap()
+      .unwrap();
+    assert_eq!(payout.amount.value(), 30);
+    assert_eq!(account.get_balance(), 0);
+    assert_eq!(account.get_pending_balance(now), 0);
   }
 }
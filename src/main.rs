@@ -1,104 +1,137 @@
-use chrono::NaiveDate;
+use chrono::{Datelike, NaiveDate, Utc, Weekday};
 use gzlib::proto::{
   self,
   loyalty::{
     loyalty_server::{Loyalty, LoyaltyServer},
-    Account, BurnRequest, Card, CardRequest, ClosePurchaseRequest, CustomerRequest,
-    LoyaltyLevelRequest, NewAccount, PurchaseSummary, QueryRequest, SetBirthdateRequest,
-    Transaction, TransactionAllRequest,
+    Account, AccountRequest, AccountStatusRequest, ActivateCardRequest, AdjustRequest,
+    AdjustmentDecision, AdjustmentRequest, AdjustmentResult, BurnRequest, Campaign,
+    CampaignAllRequest, CampaignRequest, CancelBurnRequest, Card, CardBatch, CardBatchAllRequest,
+    CardBatchStatusRequest, CardRequest, CardStateRequest, ClosePurchaseRequest, CustomerRequest,
+    DataExport, ErasureRequest, Household, HouseholdLeaveRequest, HouseholdLinkRequest,
+    HouseholdRequest, LinkCustomerRequest, LoyaltyLevelRequest, MergeRequest, NewAccount,
+    NewCampaign, NewCardAccount, NewCardBatch, PendingAdjustmentsRequest, PurchaseSummary,
+    QueryRequest, RefundRequest, ReleaseRequest, ReplaceCardRequest, Reservation, ReserveRequest,
+    SetBirthdateRequest, Transaction, TransactionAllRequest, TransferRequest, TransferResult,
   },
 };
-pub use loyalty_microservice::{loyalty, loyalty::AccountExt, prelude};
+pub use loyalty_microservice::{
+  amount::{Money, Points},
+  campaign, card,
+  config::LoyaltyConfig,
+  household, loyalty,
+  loyalty::AccountExt,
+  prelude,
+};
 use packman::VecPack;
 use prelude::*;
 use std::error::Error;
 use std::path::PathBuf;
-use std::{env, str::FromStr};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{convert::TryFrom, env, str::FromStr};
 use tokio::sync::{oneshot, Mutex};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
 use uuid::Uuid;
 
 struct LoyaltyService {
-  accounts: Mutex<VecPack<loyalty::Account>>,
+  accounts: Arc<Mutex<VecPack<loyalty::Account>>>,
+  campaigns: Mutex<VecPack<campaign::Campaign>>,
+  // Locked after accounts when both are needed
+  card_index: Mutex<card::CardIndex>,
+  // Locked after card index when both are needed
+  card_batches: Mutex<VecPack<card::CardBatch>>,
+  // Locked after card index when both are needed
+  erased_cards: Mutex<VecPack<card::ErasedCard>>,
+  config: LoyaltyConfig,
 }
 
 impl LoyaltyService {
-  fn init(accounts: VecPack<loyalty::Account>) -> Self {
+  fn init(
+    accounts: Arc<Mutex<VecPack<loyalty::Account>>>,
+    campaigns: Mutex<VecPack<campaign::Campaign>>,
+    card_index: Mutex<card::CardIndex>,
+    card_batches: Mutex<VecPack<card::CardBatch>>,
+    erased_cards: Mutex<VecPack<card::ErasedCard>>,
+    config: LoyaltyConfig,
+  ) -> Self {
     Self {
-      accounts: Mutex::new(accounts),
+      accounts,
+      campaigns,
+      card_index,
+      card_batches,
+      erased_cards,
+      config,
     }
   }
 
   async fn create_account(&self, r: NewAccount) -> ServiceResult<Account> {
     // Check if customer already has an account
-    if self
-      .accounts
-      .lock()
-      .await
-      .iter()
-      .find(|a| a.unpack().customer_id == r.customer_id)
-      .is_some()
-    {
-      return Err(ServiceError::bad_request(
-        "A megadott vásárlónak már van törzsvásárlói fiókja!",
-      ));
-    }
+    check_new_customer(&*self.accounts.lock().await, r.customer_id)?;
 
     // Convert String to NaiveDate
     let birthdate = NaiveDate::parse_from_str(&r.birthdate, "%Y-%m-%d")
       .map_err(|_| ServiceError::bad_request("A megadott születési dátum hibás formátumú!"))?;
 
     // Create new account
-    let new_account = loyalty::Account::new(r.customer_id, birthdate, r.created_by);
+    let new_account = loyalty::Account::new(
+      r.customer_id,
+      birthdate,
+      self.config.base_level(),
+      r.created_by,
+    );
 
     // Add new account to DB
     self.accounts.lock().await.insert(new_account.clone())?;
 
     // Get by ID
-    let res = self
-      .accounts
-      .lock()
-      .await
-      .find_id(&new_account.account_id)?
-      .unpack()
-      .clone();
+    let accounts = self.accounts.lock().await;
+    let res = accounts.find_id(&new_account.account_id)?.unpack().clone();
 
-    Ok(res.into())
+    Ok(account_response(&accounts, &self.config, res))
   }
 
   async fn get_account_by_customer_id(&self, r: CustomerRequest) -> ServiceResult<Account> {
-    let res = self
-      .accounts
-      .lock()
-      .await
+    let accounts = self.accounts.lock().await;
+    let res = accounts
       .iter()
-      .find(|a| a.unpack().customer_id == r.customer_id)
+      .find(|a| a.unpack().customer_id == Some(r.customer_id))
       .ok_or(ServiceError::bad_request(
         "A megadott vásárlónak nincs törzsvásárlói fiókja",
       ))?
       .unpack()
       .clone();
 
-    Ok(res.into())
+    Ok(account_response(&accounts, &self.config, res))
   }
 
   async fn get_account_by_card_id(&self, r: CardRequest) -> ServiceResult<Account> {
-    let res = self
-      .accounts
-      .lock()
-      .await
-      .iter()
-      .find(|a| match &a.unpack().card_id {
-        Some(_card_id) => _card_id == &r.card_id,
-        None => false,
-      })
-      .ok_or(ServiceError::bad_request(
-        "A megadott kártyához nem tartozik törzsvásárlói fiók",
-      ))?
-      .unpack()
-      .clone();
-    Ok(res.into())
+    let accounts = self.accounts.lock().await;
+    let account_id =
+      self
+        .card_index
+        .lock()
+        .await
+        .find(&r.card_id)
+        .ok_or(ServiceError::bad_request(
+          "A megadott kártyához nem tartozik törzsvásárlói fiók",
+        ))?;
+    let res = accounts.find_id(&account_id)?.unpack().clone();
+    // Only active cards identify the account
+    match res.get_card(&r.card_id).map(|c| c.state) {
+      Some(loyalty::CardState::Active) => (),
+      Some(loyalty::CardState::Blocked) => {
+        return Err(ServiceError::bad_request(
+          "A megadott kártya le van tiltva!",
+        ))
+      }
+      _ => {
+        return Err(ServiceError::bad_request(
+          "A megadott kártya nem aktív, nem használható!",
+        ))
+      }
+    }
+    Ok(account_response(&accounts, &self.config, res))
   }
 
   async fn get_account_by_query(&self, r: QueryRequest) -> ServiceResult<Account> {
@@ -108,13 +141,12 @@ impl LoyaltyService {
     })?;
 
     // Try to find account
-    let res = self
-      .accounts
-      .lock()
-      .await
+    let accounts = self.accounts.lock().await;
+    let res = accounts
       .iter()
       .find(|a| {
-        (a.unpack().customer_id == r.customer_id) && (a.unpack().customer_birthdate == birthdate)
+        (a.unpack().customer_id == Some(r.customer_id))
+          && (a.unpack().customer_birthdate == Some(birthdate))
       })
       .ok_or(ServiceError::not_found(
         "A kért fiók nem található a megadott adatok alapján!",
@@ -122,18 +154,25 @@ impl LoyaltyService {
       .unpack()
       .clone();
 
-    Ok(res.into())
+    Ok(account_response(&accounts, &self.config, res))
+  }
+
+  async fn get_account_by_id(&self, r: AccountRequest) -> ServiceResult<Account> {
+    let accounts = self.accounts.lock().await;
+    // Merged accounts redirect to the surviving one
+    let account_id = resolve_account_id(&accounts, string_to_uuid(r.account_id)?)?;
+    let res = accounts.find_id(&account_id)?.unpack().clone();
+    Ok(account_response(&accounts, &self.config, res))
   }
 
   async fn get_transactions_all(
     &self,
     r: TransactionAllRequest,
   ) -> ServiceResult<Vec<Transaction>> {
-    let res = self
-      .accounts
-      .lock()
-      .await
-      .find_id(&string_to_uuid(r.account_id)?)?
+    let accounts = self.accounts.lock().await;
+    let account_id = resolve_account_id(&accounts, string_to_uuid(r.account_id)?)?;
+    let res = accounts
+      .find_id(&account_id)?
       .unpack()
       .transactions
       .iter()
@@ -143,33 +182,207 @@ impl LoyaltyService {
   }
 
   async fn set_card(&self, r: Card) -> ServiceResult<Account> {
-    let res = self
-      .accounts
-      .lock()
-      .await
-      .find_id_mut(&string_to_uuid(r.set_to_account_id)?)?
+    let account_id = string_to_uuid(r.set_to_account_id)?;
+    let card_id =
+      loyalty::normalize_card_id(&r.card_id).map_err(|e| ServiceError::bad_request(&e))?;
+    let mut accounts = self.accounts.lock().await;
+    let mut card_index = self.card_index.lock().await;
+    let card_batches = self.card_batches.lock().await;
+    // Card must not belong to any other account
+    if !card_index.is_available(&card_id, account_id) {
+      return Err(ServiceError::already_exist(
+        "A megadott kártya már egy másik fiókhoz tartozik!",
+      ));
+    }
+    card::check_batch_card(card_batches.iter().map(|b| b.unpack()), &card_id)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    let res = accounts
+      .find_id_mut(&account_id)?
       .as_mut()
       .unpack()
-      .set_card(r.card_id)
+      .set_card(card_id)
       .map_err(|e| ServiceError::bad_request(&e))?
       .clone();
-    Ok(res.into())
+    if let Some(card) = res.cards.last() {
+      card_index.insert(&card.card_id, account_id);
+    }
+    Ok(account_response(&accounts, &self.config, res))
+  }
+
+  async fn open_card_account(&self, r: NewCardAccount) -> ServiceResult<Account> {
+    let card_id =
+      loyalty::normalize_card_id(&r.card_id).map_err(|e| ServiceError::bad_request(&e))?;
+    let mut accounts = self.accounts.lock().await;
+    let mut card_index = self.card_index.lock().await;
+    let card_batches = self.card_batches.lock().await;
+    // Any valid card not used yet,
+    // pre-printed cards only when handed out
+    if card_index.is_used(&card_id) {
+      return Err(ServiceError::already_exist(
+        "A megadott kártya már egy fiókhoz tartozik!",
+      ));
+    }
+    card::check_batch_card(card_batches.iter().map(|b| b.unpack()), &card_id)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    let mut new_account = loyalty::Account::new_anonymous(self.config.base_level(), r.created_by);
+    new_account
+      .set_card(card_id.clone())
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    accounts.insert(new_account.clone())?;
+    card_index.insert(&card_id, new_account.account_id);
+    Ok(account_response(&accounts, &self.config, new_account))
+  }
+
+  async fn link_customer(&self, r: LinkCustomerRequest) -> ServiceResult<Account> {
+    let birthdate = NaiveDate::parse_from_str(&r.birthdate, "%Y-%m-%d")
+      .map_err(|_| ServiceError::bad_request("A megadott születési dátum hibás formátumú!"))?;
+    let mut accounts = self.accounts.lock().await;
+    // Customer can have one account only
+    check_new_customer(&accounts, r.customer_id)?;
+    let res = accounts
+      .find_id_mut(&string_to_uuid(r.account_id)?)?
+      .as_mut()
+      .unpack()
+      .link_customer(r.customer_id, birthdate)
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    Ok(account_response(&accounts, &self.config, res))
+  }
+
+  async fn activate_card(&self, r: ActivateCardRequest) -> ServiceResult<Account> {
+    let card_id =
+      loyalty::normalize_card_id(&r.card_id).map_err(|e| ServiceError::bad_request(&e))?;
+    let mut accounts = self.accounts.lock().await;
+    let mut card_index = self.card_index.lock().await;
+    let card_batches = self.card_batches.lock().await;
+    // Account the card was used on before activation
+    let mut holder = match card_index.find(&card_id) {
+      Some(holder_id) => {
+        let holder = accounts.find_id(&holder_id)?.unpack().clone();
+        if !holder.is_anonymous() {
+          return Err(ServiceError::already_exist(
+            "A megadott kártya már aktiválva van!",
+          ));
+        }
+        Some(holder)
+      }
+      // Card of an erased account
+      None if card_index.is_used(&card_id) => {
+        return Err(ServiceError::already_exist(
+          "A megadott kártya már nem használható!",
+        ))
+      }
+      None => {
+        if !card_batches
+          .iter()
+          .any(|b| b.unpack().is_activatable(&card_id))
+        {
+          return Err(ServiceError::bad_request(
+            "A megadott kártya nem kiadott előre nyomtatott kártya!",
+          ));
+        }
+        None
+      }
+    };
+    // Existing account, or a new one for the customer
+    let mut account = match r.account_id.is_empty() {
+      false => accounts
+        .find_id(&string_to_uuid(r.account_id)?)?
+        .unpack()
+        .clone(),
+      true => {
+        check_new_customer(&accounts, r.customer_id)?;
+        let birthdate = NaiveDate::parse_from_str(&r.birthdate, "%Y-%m-%d")
+          .map_err(|_| ServiceError::bad_request("A megadott születési dátum hibás formátumú!"))?;
+        loyalty::Account::new(
+          r.customer_id,
+          birthdate,
+          self.config.base_level(),
+          r.created_by,
+        )
+      }
+    };
+    if Some(account.account_id) == holder.as_ref().map(|h| h.account_id) {
+      return Err(ServiceError::bad_request(
+        "A kártya nem aktiválható a saját névtelen fiókjára!",
+      ));
+    }
+    card::activate_card(
+      &card_id,
+      &mut account,
+      holder.as_mut(),
+      &self.config,
+      r.created_by,
+    )
+    .map_err(|e| ServiceError::bad_request(&e))?;
+    // Write back both accounts
+    if let Some(holder) = holder {
+      let holder_id = holder.account_id;
+      *accounts.find_id_mut(&holder_id)?.as_mut().unpack() = holder;
+    }
+    let account_id = account.account_id;
+    match accounts.find_id_mut(&account_id) {
+      Ok(stored) => *stored.as_mut().unpack() = account.clone(),
+      Err(_) => accounts.insert(account.clone())?,
+    }
+    card_index.insert(&card_id, account_id);
+    Ok(account_response(&accounts, &self.config, account))
+  }
+
+  async fn set_card_state(&self, r: CardStateRequest) -> ServiceResult<Account> {
+    let state =
+      loyalty::CardState::from_str(&r.state).map_err(|e| ServiceError::bad_request(&e))?;
+    let mut accounts = self.accounts.lock().await;
+    let res = accounts
+      .find_id_mut(&string_to_uuid(r.account_id)?)?
+      .as_mut()
+      .unpack()
+      .change_card_state(&r.card_id, state, r.reason)
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    Ok(account_response(&accounts, &self.config, res))
+  }
+
+  async fn replace_card(&self, r: ReplaceCardRequest) -> ServiceResult<Account> {
+    let account_id = string_to_uuid(r.account_id)?;
+    let new_card_id =
+      loyalty::normalize_card_id(&r.new_card_id).map_err(|e| ServiceError::bad_request(&e))?;
+    let mut accounts = self.accounts.lock().await;
+    let mut card_index = self.card_index.lock().await;
+    let card_batches = self.card_batches.lock().await;
+    // New card must not belong to any other account
+    if !card_index.is_available(&new_card_id, account_id) {
+      return Err(ServiceError::already_exist(
+        "A megadott kártya már egy másik fiókhoz tartozik!",
+      ));
+    }
+    card::check_batch_card(card_batches.iter().map(|b| b.unpack()), &new_card_id)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    let res = accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .replace_card(&r.card_id, new_card_id, r.reason)
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    if let Some(card) = res.cards.last() {
+      card_index.insert(&card.card_id, account_id);
+    }
+    Ok(account_response(&accounts, &self.config, res))
   }
 
   async fn set_loyalty_level(&self, r: LoyaltyLevelRequest) -> ServiceResult<Account> {
-    let res = self
-      .accounts
-      .lock()
-      .await
+    let mut accounts = self.accounts.lock().await;
+    let res = accounts
       .find_id_mut(&string_to_uuid(r.account_id)?)?
       .as_mut()
       .unpack()
       .set_loyalty_level(
-        loyalty::LoyaltyLevel::from_str(&r.loyalty_level)
+        loyalty::LoyaltyLevel::from_str(&r.loyalty_level, &self.config)
           .map_err(|e| ServiceError::bad_request(&e))?,
       )
       .clone();
-    Ok(res.into())
+    Ok(account_response(&accounts, &self.config, res))
   }
 
   async fn set_birthdate(&self, r: SetBirthdateRequest) -> ServiceResult<Account> {
@@ -178,19 +391,91 @@ impl LoyaltyService {
       ServiceError::bad_request("A megadott születési dátum nem megfelelő formátumú")
     })?;
 
-    let res = self
-      .accounts
-      .lock()
-      .await
+    let mut accounts = self.accounts.lock().await;
+    let res = accounts
       .find_id_mut(&string_to_uuid(r.account_id)?)?
       .as_mut()
       .unpack()
       .set_birthdate(birthdate)
       .clone();
-    Ok(res.into())
+    Ok(account_response(&accounts, &self.config, res))
   }
 
   async fn burn_points(&self, r: BurnRequest) -> ServiceResult<Transaction> {
+    let account_id = string_to_uuid(r.account_id)?;
+    let purchase_id = string_to_uuid(r.purchase_id)?;
+    let points_to_burn = to_points(r.points_to_burn)?;
+    let mut accounts = self.accounts.lock().await;
+
+    // Household members burn from the shared pool
+    if let Some(household_id) = accounts.find_id(&account_id)?.unpack().household_id {
+      let (mut members, mut others): (Vec<loyalty::Account>, Vec<loyalty::Account>) =
+        household_members(&accounts, household_id)
+          .into_iter()
+          .partition(|m| m.account_id == account_id);
+      let member = members
+        .first_mut()
+        .ok_or(ServiceError::internal_error("Household member not found"))?;
+      let res = household::burn_from_pool(
+        member,
+        &mut others,
+        purchase_id,
+        points_to_burn,
+        &self.config,
+        r.created_by,
+      )
+      .map_err(|e| ServiceError::bad_request(&e))?;
+      for account in members.into_iter().chain(others) {
+        let account_id = account.account_id;
+        *accounts.find_id_mut(&account_id)?.as_mut().unpack() = account;
+      }
+      return Ok(res.into());
+    }
+
+    let res = accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .burn_points(purchase_id, points_to_burn, r.created_by)
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    Ok(res.into())
+  }
+
+  async fn cancel_burn(&self, r: CancelBurnRequest) -> ServiceResult<Transaction> {
+    let account_id = string_to_uuid(r.account_id)?;
+    let purchase_id = string_to_uuid(r.purchase_id)?;
+    let mut accounts = self.accounts.lock().await;
+
+    // Points pooled from household members go back to them
+    if let Some(household_id) = accounts.find_id(&account_id)?.unpack().household_id {
+      let (mut members, mut others): (Vec<loyalty::Account>, Vec<loyalty::Account>) =
+        household_members(&accounts, household_id)
+          .into_iter()
+          .partition(|m| m.account_id == account_id);
+      let member = members
+        .first_mut()
+        .ok_or(ServiceError::internal_error("Household member not found"))?;
+      let res =
+        household::cancel_pooled_burn(member, &mut others, purchase_id, &self.config, r.created_by)
+          .map_err(|e| ServiceError::bad_request(&e))?;
+      for account in members.into_iter().chain(others) {
+        let account_id = account.account_id;
+        *accounts.find_id_mut(&account_id)?.as_mut().unpack() = account;
+      }
+      return Ok(res.into());
+    }
+
+    let res = accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .cancel_burn(purchase_id, r.created_by)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    Ok(res.into())
+  }
+
+  async fn reserve_points(&self, r: ReserveRequest) -> ServiceResult<Reservation> {
     let res = self
       .accounts
       .lock()
@@ -198,34 +483,61 @@ impl LoyaltyService {
       .find_id_mut(&string_to_uuid(r.account_id)?)?
       .as_mut()
       .unpack()
-      .burn_points(
+      .reserve_points(
         string_to_uuid(r.purchase_id)?,
-        r.points_to_burn,
+        to_points(r.points_to_reserve)?,
+        &self.config,
         r.created_by,
       )
-      .map_err(|e| ServiceError::bad_request(&e))?
-      .clone();
+      .map_err(|e| ServiceError::bad_request(&e))?;
     Ok(res.into())
   }
 
+  async fn release_reservation(&self, r: ReleaseRequest) -> ServiceResult<Account> {
+    let mut accounts = self.accounts.lock().await;
+    let mut account = accounts
+      .find_id_mut(&string_to_uuid(r.account_id)?)?
+      .as_mut();
+    account
+      .unpack()
+      .release_reservation(string_to_uuid(r.purchase_id)?)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    let res = account.unpack().clone();
+    Ok(account_response(&accounts, &self.config, res))
+  }
+
   async fn close_purchase(&self, r: ClosePurchaseRequest) -> ServiceResult<PurchaseSummary> {
-    let summary = self
-      .accounts
+    let campaigns = self
+      .campaigns
       .lock()
       .await
-      .find_id_mut(&string_to_uuid(r.account_id.clone())?)?
+      .iter()
+      .map(|c| c.unpack().clone())
+      .collect::<Vec<campaign::Campaign>>();
+
+    let account_id = string_to_uuid(r.account_id.clone())?;
+    let mut accounts = self.accounts.lock().await;
+    let summary = accounts
+      .find_id_mut(&account_id)?
       .as_mut()
       .unpack()
       .close_purchase(
         loyalty::PurchaseInfo {
           purchase_id: string_to_uuid(r.purchase_id.clone())?,
-          payable_total_gross: r.total_gross,
+          payable_total_gross: to_money(r.total_gross)?,
           created_by: r.created_by,
         },
+        &self.config,
+        &campaigns,
         r.created_by,
       )
       .map_err(|e| ServiceError::bad_request(&e))?;
 
+    // Turnover counts for the whole household
+    if let Some(household_id) = accounts.find_id(&account_id)?.unpack().household_id {
+      sync_household(&mut accounts, household_id, &self.config)?;
+    }
+
     Ok(PurchaseSummary {
       account_id: r.account_id,
       purchase_id: r.purchase_id,
@@ -235,93 +547,759 @@ impl LoyaltyService {
       balance_closing: summary.balance_closing,
     })
   }
-}
-
-// Helper to try convert string to UUID
-fn string_to_uuid(id: String) -> ServiceResult<Uuid> {
-  Uuid::from_str(&id).map_err(|_| ServiceError::BadRequest(format!("A kért ID hibás: {}", id)))
-}
 
-#[tonic::async_trait]
-impl Loyalty for LoyaltyService {
-  async fn create_account(
-    &self,
-    request: Request<proto::loyalty::NewAccount>,
-  ) -> Result<Response<proto::loyalty::Account>, Status> {
-    let res = self.create_account(request.into_inner()).await?;
-    Ok(Response::new(res))
-  }
+  async fn refund_purchase(&self, r: RefundRequest) -> ServiceResult<Transaction> {
+    let account_id = string_to_uuid(r.account_id)?;
+    let mut accounts = self.accounts.lock().await;
+    let res = accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .refund_purchase(
+        string_to_uuid(r.purchase_id)?,
+        to_money(r.refund_amount)?,
+        &self.config,
+        r.created_by,
+      )
+      .map_err(|e| ServiceError::bad_request(&e))?;
 
-  async fn get_account_by_customer_id(
-    &self,
-    request: Request<proto::loyalty::CustomerRequest>,
-  ) -> Result<Response<proto::loyalty::Account>, Status> {
-    let res = self
-      .get_account_by_customer_id(request.into_inner())
-      .await?;
-    Ok(Response::new(res))
+    // Refunded turnover is taken from the household as well
+    if let Some(household_id) = accounts.find_id(&account_id)?.unpack().household_id {
+      sync_household(&mut accounts, household_id, &self.config)?;
+    }
+    Ok(res.into())
   }
 
-  async fn get_account_by_card_id(
-    &self,
-    request: Request<proto::loyalty::CardRequest>,
-  ) -> Result<Response<proto::loyalty::Account>, Status> {
-    let res = self.get_account_by_card_id(request.into_inner()).await?;
-    Ok(Response::new(res))
+  async fn link_household(&self, r: HouseholdLinkRequest) -> ServiceResult<Household> {
+    let account_id = string_to_uuid(r.account_id)?;
+    let link_to_id = string_to_uuid(r.link_to_account_id)?;
+    if account_id == link_to_id {
+      return Err(ServiceError::bad_request(
+        "A fiók nem kapcsolható önmagához!",
+      ));
+    }
+    let mut accounts = self.accounts.lock().await;
+    // Join the household of the other account, or found a new one
+    let household_id = accounts
+      .find_id(&link_to_id)?
+      .unpack()
+      .household_id
+      .unwrap_or_else(Uuid::new_v4);
+    // Check before changing any of them
+    accounts
+      .find_id(&account_id)?
+      .unpack()
+      .clone()
+      .join_household(household_id)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    for id in &[link_to_id, account_id] {
+      accounts
+        .find_id_mut(id)?
+        .as_mut()
+        .unpack()
+        .join_household(household_id)
+        .map_err(|e| ServiceError::bad_request(&e))?;
+    }
+    sync_household(&mut accounts, household_id, &self.config)?;
+    Ok(household_response(&accounts, household_id))
   }
 
-  async fn get_account_by_query(
-    &self,
-    request: Request<proto::loyalty::QueryRequest>,
-  ) -> Result<Response<proto::loyalty::Account>, Status> {
-    let res = self.get_account_by_query(request.into_inner()).await?;
-    Ok(Response::new(res))
+  async fn leave_household(&self, r: HouseholdLeaveRequest) -> ServiceResult<Account> {
+    let account_id = string_to_uuid(r.account_id)?;
+    let mut accounts = self.accounts.lock().await;
+    let household_id = accounts.find_id(&account_id)?.unpack().household_id;
+    let res = accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .leave_household()
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    // Remaining members do not count the leaving member's turnover anymore
+    if let Some(household_id) = household_id {
+      sync_household(&mut accounts, household_id, &self.config)?;
+    }
+    Ok(account_response(&accounts, &self.config, res))
   }
 
-  type GetTransactionsAllStream = ReceiverStream<Result<Transaction, Status>>;
-
-  async fn get_transactions_all(
-    &self,
-    request: Request<proto::loyalty::TransactionAllRequest>,
-  ) -> Result<Response<Self::GetTransactionsAllStream>, Status> {
-    // Create channel for stream response
-    let (mut tx, rx) = tokio::sync::mpsc::channel(100);
-
-    // Get resources as Vec<SourceObject>
-    let res = self.get_transactions_all(request.into_inner()).await?;
-
-    // Send the result items through the channel
-    tokio::spawn(async move {
-      for ots in res.into_iter() {
-        tx.send(Ok(ots)).await.unwrap();
+  async fn erase_customer_data(&self, r: ErasureRequest) -> ServiceResult<Account> {
+    let mut accounts = self.accounts.lock().await;
+    let mut card_index = self.card_index.lock().await;
+    let mut erased_cards = self.erased_cards.lock().await;
+    let account_id = accounts
+      .iter()
+      .find(|a| a.unpack().customer_id == Some(r.customer_id))
+      .ok_or(ServiceError::not_found(
+        "A megadott vásárlónak nincs törzsvásárlói fiókja",
+      ))?
+      .unpack()
+      .account_id;
+    let household_id = accounts.find_id(&account_id)?.unpack().household_id;
+    let mut card_ids = accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .erase_personal_data(r.created_by)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    // Tombstones of the accounts merged into it
+    for merged_id in merged_account_ids(&accounts, account_id) {
+      card_ids.extend(
+        loyalty::erase_tombstone(
+          accounts.find_id_mut(&merged_id)?.as_mut().unpack(),
+          r.created_by,
+        )
+        .map_err(|e| ServiceError::internal_error(&e))?,
+      );
+    }
+    // Card IDs stay reserved, without the account
+    for card_id in card_ids.iter() {
+      card_index.reserve(card_id);
+      if erased_cards.find_id(card_id).is_err() {
+        erased_cards.insert(card::ErasedCard {
+          card_id: card_id.to_string(),
+        })?;
       }
-    });
-
-    // Send back the receiver
-    Ok(Response::new(ReceiverStream::new(rx)))
+    }
+    // Remaining members do not count the erased account's turnover anymore
+    if let Some(household_id) = household_id {
+      sync_household(&mut accounts, household_id, &self.config)?;
+    }
+    let res = accounts.find_id(&account_id)?.unpack().clone();
+    Ok(account_response(&accounts, &self.config, res))
   }
 
-  async fn set_card(
-    &self,
-    request: Request<proto::loyalty::Card>,
-  ) -> Result<Response<proto::loyalty::Account>, Status> {
-    let res = self.set_card(request.into_inner()).await?;
-    Ok(Response::new(res))
+  async fn export_customer_data(&self, r: CustomerRequest) -> ServiceResult<DataExport> {
+    let accounts = self.accounts.lock().await;
+    let account = accounts
+      .iter()
+      .find(|a| a.unpack().customer_id == Some(r.customer_id))
+      .ok_or(ServiceError::not_found(
+        "A megadott vásárlónak nincs törzsvásárlói fiókja",
+      ))?
+      .unpack()
+      .clone();
+    // Tombstones of the accounts merged into it
+    let merged_accounts = merged_account_ids(&accounts, account.account_id)
+      .into_iter()
+      .map(|merged_id| Ok(accounts.find_id(&merged_id)?.unpack().clone()))
+      .collect::<ServiceResult<Vec<loyalty::Account>>>()?;
+    let content = account
+      .export_personal_data(&merged_accounts)
+      .map_err(|e| ServiceError::internal_error(&e))?;
+    Ok(DataExport {
+      customer_id: r.customer_id,
+      file_name: format!(
+        "loyalty_{}_{}.json",
+        r.customer_id,
+        Utc::now().format("%Y%m%d%H%M%S")
+      ),
+      content,
+    })
   }
 
-  async fn set_loyalty_level(
-    &self,
-    request: Request<proto::loyalty::LoyaltyLevelRequest>,
-  ) -> Result<Response<proto::loyalty::Account>, Status> {
-    let res = self.set_loyalty_level(request.into_inner()).await?;
-    Ok(Response::new(res))
+  async fn get_household(&self, r: HouseholdRequest) -> ServiceResult<Household> {
+    let household_id = string_to_uuid(r.household_id)?;
+    let accounts = self.accounts.lock().await;
+    if household_members(&accounts, household_id).is_empty() {
+      return Err(ServiceError::not_found(
+        "A megadott háztartás nem található!",
+      ));
+    }
+    Ok(household_response(&accounts, household_id))
   }
 
-  async fn set_birthdate(
-    &self,
-    request: Request<proto::loyalty::SetBirthdateRequest>,
-  ) -> Result<Response<proto::loyalty::Account>, Status> {
-    let res = self.set_birthdate(request.into_inner()).await?;
+  async fn adjust_points(&self, r: AdjustRequest) -> ServiceResult<AdjustmentResult> {
+    let reason =
+      loyalty::AdjustmentReason::from_str(&r.reason).map_err(|e| ServiceError::bad_request(&e))?;
+    let res = self
+      .accounts
+      .lock()
+      .await
+      .find_id_mut(&string_to_uuid(r.account_id)?)?
+      .as_mut()
+      .unpack()
+      .request_adjustment(r.amount, reason, r.notes, &self.config, r.created_by)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    Ok(res.into())
+  }
+
+  async fn approve_adjustment(&self, r: AdjustmentDecision) -> ServiceResult<Transaction> {
+    let res = self
+      .accounts
+      .lock()
+      .await
+      .find_id_mut(&string_to_uuid(r.account_id)?)?
+      .as_mut()
+      .unpack()
+      .approve_adjustment(string_to_uuid(r.request_id)?, r.decided_by, &self.config)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    Ok(res.into())
+  }
+
+  async fn reject_adjustment(&self, r: AdjustmentDecision) -> ServiceResult<AdjustmentRequest> {
+    let res = self
+      .accounts
+      .lock()
+      .await
+      .find_id_mut(&string_to_uuid(r.account_id)?)?
+      .as_mut()
+      .unpack()
+      .reject_adjustment(string_to_uuid(r.request_id)?, r.decided_by)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    Ok(res.into())
+  }
+
+  async fn get_pending_adjustments_all(
+    &self,
+    _: PendingAdjustmentsRequest,
+  ) -> ServiceResult<Vec<AdjustmentRequest>> {
+    let now = Utc::now();
+    let res = self
+      .accounts
+      .lock()
+      .await
+      .iter()
+      .flat_map(|a| a.unpack().adjustment_requests.clone())
+      .filter(|r| r.status == loyalty::AdjustmentStatus::Pending && r.expires_at > now)
+      .map(|r| r.into())
+      .collect::<Vec<AdjustmentRequest>>();
+    Ok(res)
+  }
+
+  async fn set_account_status(&self, r: AccountStatusRequest) -> ServiceResult<Account> {
+    let status =
+      loyalty::AccountStatus::from_str(&r.status).map_err(|e| ServiceError::bad_request(&e))?;
+    let mut accounts = self.accounts.lock().await;
+    let res = {
+      let mut account = accounts
+        .find_id_mut(&string_to_uuid(r.account_id)?)?
+        .as_mut();
+      account
+        .unpack()
+        .set_status(status, r.reason, &self.config, r.created_by)
+        .map_err(|e| ServiceError::bad_request(&e))?;
+      account.unpack().clone()
+    };
+    Ok(account_response(&accounts, &self.config, res))
+  }
+
+  async fn merge_accounts(&self, r: MergeRequest) -> ServiceResult<Account> {
+    let survivor_id = string_to_uuid(r.account_id)?;
+    let merged_id = string_to_uuid(r.merged_account_id)?;
+    // Both accounts and the card index change under the same lock
+    let mut accounts = self.accounts.lock().await;
+    let mut card_index = self.card_index.lock().await;
+    let mut survivor = accounts.find_id(&survivor_id)?.unpack().clone();
+    let mut merged = accounts.find_id(&merged_id)?.unpack().clone();
+    let merge = loyalty::merge_accounts(&mut survivor, &mut merged, &self.config, r.created_by)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    *accounts.find_id_mut(&survivor_id)?.as_mut().unpack() = survivor;
+    *accounts.find_id_mut(&merged_id)?.as_mut().unpack() = merged;
+    for card_id in merge.card_ids.iter() {
+      card_index.insert(card_id, survivor_id);
+    }
+    // Merged turnover counts for the survivor's household
+    if let Some(household_id) = accounts.find_id(&survivor_id)?.unpack().household_id {
+      sync_household(&mut accounts, household_id, &self.config)?;
+    }
+    let res = accounts.find_id(&survivor_id)?.unpack().clone();
+    Ok(account_response(&accounts, &self.config, res))
+  }
+
+  async fn transfer_points(&self, r: TransferRequest) -> ServiceResult<TransferResult> {
+    let from_id = string_to_uuid(r.from_account_id)?;
+    let to_id = string_to_uuid(r.to_account_id)?;
+    let points = to_points(r.points)?;
+    // Both accounts change under the same lock
+    let mut accounts = self.accounts.lock().await;
+    let mut from = accounts.find_id(&from_id)?.unpack().clone();
+    let mut to = accounts.find_id(&to_id)?.unpack().clone();
+    let transfer = loyalty::transfer_points(&mut from, &mut to, points, &self.config, r.created_by)
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    *accounts.find_id_mut(&from_id)?.as_mut().unpack() = from;
+    *accounts.find_id_mut(&to_id)?.as_mut().unpack() = to;
+    Ok(transfer.into())
+  }
+
+  async fn create_campaign(&self, r: NewCampaign) -> ServiceResult<Campaign> {
+    let start_date = NaiveDate::parse_from_str(&r.start_date, "%Y-%m-%d")
+      .map_err(|_| ServiceError::bad_request("A kampány kezdete hibás formátumú!"))?;
+    let end_date = NaiveDate::parse_from_str(&r.end_date, "%Y-%m-%d")
+      .map_err(|_| ServiceError::bad_request("A kampány vége hibás formátumú!"))?;
+    let weekdays = r
+      .weekdays
+      .into_iter()
+      .map(to_weekday)
+      .collect::<ServiceResult<Vec<Weekday>>>()?;
+    let loyalty_levels = r
+      .loyalty_levels
+      .iter()
+      .map(|l| loyalty::LoyaltyLevel::from_str(l, &self.config))
+      .collect::<Result<Vec<loyalty::LoyaltyLevel>, String>>()
+      .map_err(|e| ServiceError::bad_request(&e))?;
+    // Multiplier if set, flat bonus otherwise
+    let reward = match r.multiplier_percentage {
+      0 => campaign::CampaignReward::Bonus {
+        points: r.bonus_points,
+      },
+      percentage => campaign::CampaignReward::Multiplier { percentage },
+    };
+
+    let new_campaign = campaign::Campaign::new(
+      r.name,
+      start_date,
+      end_date,
+      weekdays,
+      loyalty_levels,
+      reward,
+      r.created_by,
+    )
+    .map_err(|e| ServiceError::bad_request(&e))?;
+
+    self.campaigns.lock().await.insert(new_campaign.clone())?;
+
+    Ok(new_campaign.into())
+  }
+
+  async fn close_campaign(&self, r: CampaignRequest) -> ServiceResult<Campaign> {
+    let res = self
+      .campaigns
+      .lock()
+      .await
+      .find_id_mut(&string_to_uuid(r.campaign_id)?)?
+      .as_mut()
+      .unpack()
+      .close(Utc::today().naive_utc())
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    Ok(res.into())
+  }
+
+  async fn get_campaigns_all(&self, _: CampaignAllRequest) -> ServiceResult<Vec<Campaign>> {
+    let res = self
+      .campaigns
+      .lock()
+      .await
+      .iter()
+      .map(|c| c.unpack().clone().into())
+      .collect::<Vec<Campaign>>();
+    Ok(res)
+  }
+
+  async fn allocate_card_batch(&self, r: NewCardBatch) -> ServiceResult<CardBatch> {
+    let card_index = self.card_index.lock().await;
+    let mut card_batches = self.card_batches.lock().await;
+    let batches = card_batches
+      .iter()
+      .map(|b| b.unpack().clone())
+      .collect::<Vec<card::CardBatch>>();
+    let new_batch = card::CardBatch::allocate(
+      &self.config.card_ranges,
+      &batches,
+      &card_index,
+      r.count,
+      r.created_by,
+    )
+    .map_err(|e| ServiceError::bad_request(&e))?;
+
+    card_batches.insert(new_batch.clone())?;
+
+    Ok(new_batch.into())
+  }
+
+  async fn set_card_batch_status(&self, r: CardBatchStatusRequest) -> ServiceResult<CardBatch> {
+    let status =
+      card::BatchStatus::from_str(&r.status).map_err(|e| ServiceError::bad_request(&e))?;
+    let res = self
+      .card_batches
+      .lock()
+      .await
+      .find_id_mut(&string_to_uuid(r.batch_id)?)?
+      .as_mut()
+      .unpack()
+      .set_status(status)
+      .map_err(|e| ServiceError::bad_request(&e))?
+      .clone();
+    Ok(res.into())
+  }
+
+  async fn get_card_batches_all(&self, _: CardBatchAllRequest) -> ServiceResult<Vec<CardBatch>> {
+    let res = self
+      .card_batches
+      .lock()
+      .await
+      .iter()
+      .map(|b| b.unpack().clone().into())
+      .collect::<Vec<CardBatch>>();
+    Ok(res)
+  }
+}
+
+// Yearly requalification of every account
+// where it is due
+async fn requalify_accounts(
+  accounts: &Mutex<VecPack<loyalty::Account>>,
+  config: &LoyaltyConfig,
+) -> ServiceResult<()> {
+  let mut accounts = accounts.lock().await;
+  let now = Utc::now();
+  // Collect due accounts first, so only those are saved
+  let due = accounts
+    .iter()
+    .filter(|a| a.unpack().is_requalification_due(config, now))
+    .map(|a| a.unpack().account_id)
+    .collect::<Vec<Uuid>>();
+  for account_id in due {
+    accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .requalify(config, now);
+  }
+  Ok(())
+}
+
+// Upgrade accounts stored by an earlier version
+fn upgrade_accounts(
+  accounts: &mut VecPack<loyalty::Account>,
+  config: &LoyaltyConfig,
+) -> ServiceResult<()> {
+  let now = Utc::now();
+  let due = accounts
+    .iter()
+    .filter(|a| a.unpack().needs_upgrade())
+    .map(|a| a.unpack().account_id)
+    .collect::<Vec<Uuid>>();
+  for account_id in due {
+    accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .upgrade(config, now)
+      .map_err(|e| ServiceError::internal_error(&e))?;
+  }
+  Ok(())
+}
+
+// Write expiry transactions for every lapsed point lot
+async fn expire_points(accounts: &Mutex<VecPack<loyalty::Account>>) -> ServiceResult<()> {
+  let mut accounts = accounts.lock().await;
+  let now = Utc::now();
+  let due = accounts
+    .iter()
+    .filter(|a| a.unpack().has_expired_points(now))
+    .map(|a| a.unpack().account_id)
+    .collect::<Vec<Uuid>>();
+  for account_id in due {
+    accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .expire_points(now)
+      .map_err(|e| ServiceError::internal_error(&e))?;
+  }
+  Ok(())
+}
+
+// Release reservations of purchases not closed in time
+async fn release_expired_reservations(
+  accounts: &Mutex<VecPack<loyalty::Account>>,
+) -> ServiceResult<()> {
+  let mut accounts = accounts.lock().await;
+  let now = Utc::now();
+  let due = accounts
+    .iter()
+    .filter(|a| a.unpack().has_expired_reservations(now))
+    .map(|a| a.unpack().account_id)
+    .collect::<Vec<Uuid>>();
+  for account_id in due {
+    accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .release_expired_reservations(now);
+  }
+  Ok(())
+}
+
+// Expire adjustment requests not decided in time
+async fn expire_adjustment_requests(
+  accounts: &Mutex<VecPack<loyalty::Account>>,
+) -> ServiceResult<()> {
+  let mut accounts = accounts.lock().await;
+  let now = Utc::now();
+  let due = accounts
+    .iter()
+    .filter(|a| a.unpack().has_expired_adjustment_requests(now))
+    .map(|a| a.unpack().account_id)
+    .collect::<Vec<Uuid>>();
+  for account_id in due {
+    accounts
+      .find_id_mut(&account_id)?
+      .as_mut()
+      .unpack()
+      .expire_adjustment_requests(now);
+  }
+  Ok(())
+}
+
+// Check that the customer has no account yet
+fn check_new_customer(accounts: &VecPack<loyalty::Account>, customer_id: u32) -> ServiceResult<()> {
+  if accounts
+    .iter()
+    .any(|a| a.unpack().customer_id == Some(customer_id))
+  {
+    return Err(ServiceError::bad_request(
+      "A megadott vásárlónak már van törzsvásárlói fiókja!",
+    ));
+  }
+  Ok(())
+}
+
+// Accounts merged into the given one,
+// directly or through earlier merges
+fn merged_account_ids(accounts: &VecPack<loyalty::Account>, account_id: Uuid) -> Vec<Uuid> {
+  let mut account_ids = vec![account_id];
+  let mut i = 0;
+  while i < account_ids.len() {
+    let into_account_id = account_ids[i];
+    account_ids.extend(
+      accounts
+        .iter()
+        .map(|a| a.unpack())
+        .filter(|a| a.merged_into == Some(into_account_id))
+        .map(|a| a.account_id),
+    );
+    i += 1;
+  }
+  account_ids.remove(0);
+  account_ids
+}
+
+// Accounts of the given household
+fn household_members(
+  accounts: &VecPack<loyalty::Account>,
+  household_id: Uuid,
+) -> Vec<loyalty::Account> {
+  accounts
+    .iter()
+    .map(|a| a.unpack())
+    .filter(|a| a.household_id == Some(household_id))
+    .cloned()
+    .collect()
+}
+
+// Share turnover among the household members after a change
+fn sync_household(
+  accounts: &mut VecPack<loyalty::Account>,
+  household_id: Uuid,
+  config: &LoyaltyConfig,
+) -> ServiceResult<()> {
+  let mut members = household_members(accounts, household_id);
+  household::sync_household(&mut members, config).map_err(|e| ServiceError::internal_error(&e))?;
+  for member in members {
+    let account_id = member.account_id;
+    *accounts.find_id_mut(&account_id)?.as_mut().unpack() = member;
+  }
+  Ok(())
+}
+
+// ID of the account that took over the given one by merges,
+// or the given ID if it was not merged
+fn resolve_account_id(
+  accounts: &VecPack<loyalty::Account>,
+  account_id: Uuid,
+) -> ServiceResult<Uuid> {
+  let mut account_id = account_id;
+  while let Some(merged_into) = accounts.find_id(&account_id)?.unpack().merged_into {
+    account_id = merged_into;
+  }
+  Ok(account_id)
+}
+
+// Account with its tier name and household balance
+fn account_response(
+  accounts: &VecPack<loyalty::Account>,
+  config: &LoyaltyConfig,
+  account: loyalty::Account,
+) -> Account {
+  let household_balance = account.household_id.map(|household_id| {
+    household::get_household_balance(household_members(accounts, household_id).iter())
+  });
+  let loyalty_level_name = config
+    .get_tier(&account.loyalty_level)
+    .map(|tier| tier.name.clone())
+    .unwrap_or_default();
+  let mut res: Account = account.into();
+  res.loyalty_level_name = loyalty_level_name;
+  if let Some(household_balance) = household_balance {
+    res.household_balance_points = household_balance;
+  }
+  res
+}
+
+fn household_response(accounts: &VecPack<loyalty::Account>, household_id: Uuid) -> Household {
+  let members = household_members(accounts, household_id);
+  Household {
+    household_id: household_id.to_string(),
+    member_account_ids: members.iter().map(|m| m.account_id.to_string()).collect(),
+    balance_points: household::get_household_balance(members.iter()),
+    yearly_gross_turnover: household::get_household_turnover(members.iter(), Utc::today().year()),
+  }
+}
+
+// Helper to try convert string to UUID
+fn string_to_uuid(id: String) -> ServiceResult<Uuid> {
+  Uuid::from_str(&id).map_err(|_| ServiceError::BadRequest(format!("A kért ID hibás: {}", id)))
+}
+
+// Helper to validate points amount
+fn to_points(value: i32) -> ServiceResult<Points> {
+  Points::new(value).map_err(|e| ServiceError::bad_request(&e))
+}
+
+// Helper to validate money amount
+fn to_money(value: u32) -> ServiceResult<Money> {
+  Money::try_from(value).map_err(|e| ServiceError::bad_request(&e))
+}
+
+// Helper to convert day of the week, 1 => Monday
+fn to_weekday(value: u32) -> ServiceResult<Weekday> {
+  match value {
+    1 => Ok(Weekday::Mon),
+    2 => Ok(Weekday::Tue),
+    3 => Ok(Weekday::Wed),
+    4 => Ok(Weekday::Thu),
+    5 => Ok(Weekday::Fri),
+    6 => Ok(Weekday::Sat),
+    7 => Ok(Weekday::Sun),
+    _ => Err(ServiceError::BadRequest(format!(
+      "Hibás nap: {}. Lehetséges értékek: 1-7",
+      value
+    ))),
+  }
+}
+
+#[tonic::async_trait]
+impl Loyalty for LoyaltyService {
+  async fn create_account(
+    &self,
+    request: Request<proto::loyalty::NewAccount>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.create_account(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_account_by_customer_id(
+    &self,
+    request: Request<proto::loyalty::CustomerRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self
+      .get_account_by_customer_id(request.into_inner())
+      .await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_account_by_card_id(
+    &self,
+    request: Request<proto::loyalty::CardRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.get_account_by_card_id(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_account_by_query(
+    &self,
+    request: Request<proto::loyalty::QueryRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.get_account_by_query(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type GetTransactionsAllStream = ReceiverStream<Result<Transaction, Status>>;
+
+  async fn get_transactions_all(
+    &self,
+    request: Request<proto::loyalty::TransactionAllRequest>,
+  ) -> Result<Response<Self::GetTransactionsAllStream>, Status> {
+    // Create channel for stream response
+    let (mut tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get resources as Vec<SourceObject>
+    let res = self.get_transactions_all(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn set_card(
+    &self,
+    request: Request<proto::loyalty::Card>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.set_card(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn open_card_account(
+    &self,
+    request: Request<proto::loyalty::NewCardAccount>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.open_card_account(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn link_customer(
+    &self,
+    request: Request<proto::loyalty::LinkCustomerRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.link_customer(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn activate_card(
+    &self,
+    request: Request<proto::loyalty::ActivateCardRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.activate_card(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_card_state(
+    &self,
+    request: Request<proto::loyalty::CardStateRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.set_card_state(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn replace_card(
+    &self,
+    request: Request<proto::loyalty::ReplaceCardRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.replace_card(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_loyalty_level(
+    &self,
+    request: Request<proto::loyalty::LoyaltyLevelRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.set_loyalty_level(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_birthdate(
+    &self,
+    request: Request<proto::loyalty::SetBirthdateRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.set_birthdate(request.into_inner()).await?;
     Ok(Response::new(res))
   }
 
@@ -333,6 +1311,30 @@ impl Loyalty for LoyaltyService {
     Ok(Response::new(res))
   }
 
+  async fn cancel_burn(
+    &self,
+    request: Request<proto::loyalty::CancelBurnRequest>,
+  ) -> Result<Response<proto::loyalty::Transaction>, Status> {
+    let res = self.cancel_burn(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn reserve_points(
+    &self,
+    request: Request<proto::loyalty::ReserveRequest>,
+  ) -> Result<Response<proto::loyalty::Reservation>, Status> {
+    let res = self.reserve_points(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn release_reservation(
+    &self,
+    request: Request<proto::loyalty::ReleaseRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.release_reservation(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
   async fn close_purchase(
     &self,
     request: Request<proto::loyalty::ClosePurchaseRequest>,
@@ -340,15 +1342,291 @@ impl Loyalty for LoyaltyService {
     let res = self.close_purchase(request.into_inner()).await?;
     Ok(Response::new(res))
   }
+
+  async fn refund_purchase(
+    &self,
+    request: Request<proto::loyalty::RefundRequest>,
+  ) -> Result<Response<proto::loyalty::Transaction>, Status> {
+    let res = self.refund_purchase(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn adjust_points(
+    &self,
+    request: Request<proto::loyalty::AdjustRequest>,
+  ) -> Result<Response<proto::loyalty::AdjustmentResult>, Status> {
+    let res = self.adjust_points(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn approve_adjustment(
+    &self,
+    request: Request<proto::loyalty::AdjustmentDecision>,
+  ) -> Result<Response<proto::loyalty::Transaction>, Status> {
+    let res = self.approve_adjustment(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn reject_adjustment(
+    &self,
+    request: Request<proto::loyalty::AdjustmentDecision>,
+  ) -> Result<Response<proto::loyalty::AdjustmentRequest>, Status> {
+    let res = self.reject_adjustment(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type GetPendingAdjustmentsAllStream = ReceiverStream<Result<AdjustmentRequest, Status>>;
+
+  async fn get_pending_adjustments_all(
+    &self,
+    request: Request<proto::loyalty::PendingAdjustmentsRequest>,
+  ) -> Result<Response<Self::GetPendingAdjustmentsAllStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get resources as Vec<SourceObject>
+    let res = self
+      .get_pending_adjustments_all(request.into_inner())
+      .await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn get_account_by_id(
+    &self,
+    request: Request<proto::loyalty::AccountRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.get_account_by_id(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_account_status(
+    &self,
+    request: Request<proto::loyalty::AccountStatusRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.set_account_status(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn merge_accounts(
+    &self,
+    request: Request<proto::loyalty::MergeRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.merge_accounts(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn transfer_points(
+    &self,
+    request: Request<proto::loyalty::TransferRequest>,
+  ) -> Result<Response<proto::loyalty::TransferResult>, Status> {
+    let res = self.transfer_points(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn link_household(
+    &self,
+    request: Request<proto::loyalty::HouseholdLinkRequest>,
+  ) -> Result<Response<proto::loyalty::Household>, Status> {
+    let res = self.link_household(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn leave_household(
+    &self,
+    request: Request<proto::loyalty::HouseholdLeaveRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.leave_household(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn erase_customer_data(
+    &self,
+    request: Request<proto::loyalty::ErasureRequest>,
+  ) -> Result<Response<proto::loyalty::Account>, Status> {
+    let res = self.erase_customer_data(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn export_customer_data(
+    &self,
+    request: Request<proto::loyalty::CustomerRequest>,
+  ) -> Result<Response<proto::loyalty::DataExport>, Status> {
+    let res = self.export_customer_data(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn get_household(
+    &self,
+    request: Request<proto::loyalty::HouseholdRequest>,
+  ) -> Result<Response<proto::loyalty::Household>, Status> {
+    let res = self.get_household(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn create_campaign(
+    &self,
+    request: Request<proto::loyalty::NewCampaign>,
+  ) -> Result<Response<proto::loyalty::Campaign>, Status> {
+    let res = self.create_campaign(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn close_campaign(
+    &self,
+    request: Request<proto::loyalty::CampaignRequest>,
+  ) -> Result<Response<proto::loyalty::Campaign>, Status> {
+    let res = self.close_campaign(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type GetCampaignsAllStream = ReceiverStream<Result<Campaign, Status>>;
+
+  async fn get_campaigns_all(
+    &self,
+    request: Request<proto::loyalty::CampaignAllRequest>,
+  ) -> Result<Response<Self::GetCampaignsAllStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get resources as Vec<SourceObject>
+    let res = self.get_campaigns_all(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn allocate_card_batch(
+    &self,
+    request: Request<proto::loyalty::NewCardBatch>,
+  ) -> Result<Response<proto::loyalty::CardBatch>, Status> {
+    let res = self.allocate_card_batch(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  async fn set_card_batch_status(
+    &self,
+    request: Request<proto::loyalty::CardBatchStatusRequest>,
+  ) -> Result<Response<proto::loyalty::CardBatch>, Status> {
+    let res = self.set_card_batch_status(request.into_inner()).await?;
+    Ok(Response::new(res))
+  }
+
+  type GetCardBatchesAllStream = ReceiverStream<Result<CardBatch, Status>>;
+
+  async fn get_card_batches_all(
+    &self,
+    request: Request<proto::loyalty::CardBatchAllRequest>,
+  ) -> Result<Response<Self::GetCardBatchesAllStream>, Status> {
+    // Create channel for stream response
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Get resources as Vec<SourceObject>
+    let res = self.get_card_batches_all(request.into_inner()).await?;
+
+    // Send the result items through the channel
+    tokio::spawn(async move {
+      for ots in res.into_iter() {
+        tx.send(Ok(ots)).await.unwrap();
+      }
+    });
+
+    // Send back the receiver
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
   // Init loyalty accounts database
-  let loyalty_accounts: VecPack<loyalty::Account> =
+  let mut loyalty_accounts: VecPack<loyalty::Account> =
     VecPack::load_or_init(PathBuf::from("data/loyalty_accounts"))
       .expect("Error while loading loyalty accounts db");
 
+  // Init campaigns database
+  let loyalty_campaigns: VecPack<campaign::Campaign> =
+    VecPack::load_or_init(PathBuf::from("data/loyalty_campaigns"))
+      .expect("Error while loading loyalty campaigns db");
+
+  // Init card batches database
+  let loyalty_card_batches: VecPack<card::CardBatch> =
+    VecPack::load_or_init(PathBuf::from("data/loyalty_card_batches"))
+      .expect("Error while loading loyalty card batches db");
+
+  // Init erased cards database
+  let loyalty_erased_cards: VecPack<card::ErasedCard> =
+    VecPack::load_or_init(PathBuf::from("data/loyalty_erased_cards"))
+      .expect("Error while loading loyalty erased cards db");
+
+  // Load loyalty config (tier table)
+  let config = LoyaltyConfig::load(PathBuf::from(
+    env::var("LOYALTY_CONFIG_PATH").unwrap_or("data/loyalty_config.json".into()),
+  ))
+  .expect("Error while loading loyalty config");
+
+  // Bring accounts stored by an earlier version up to date,
+  // before any job or request uses them
+  // and before the card index is built, so migrated cards are indexed
+  upgrade_accounts(&mut loyalty_accounts, &config).expect("Error while upgrading loyalty accounts");
+
+  // Build card index
+  // Duplicated cards of existing accounts are reported,
+  // they need to be fixed by hand
+  let (mut card_index, duplicated_cards) =
+    card::CardIndex::build(loyalty_accounts.iter().map(|a| a.unpack()));
+  // Cards of erased accounts are never reused
+  for erased_card in loyalty_erased_cards.iter() {
+    card_index.reserve(&erased_card.unpack().card_id);
+  }
+  for duplicate in duplicated_cards {
+    println!(
+      "Duplicated card ID {} on accounts: {:?}",
+      duplicate.card_id, duplicate.account_ids
+    );
+  }
+
+  let loyalty_accounts = Arc::new(Mutex::new(loyalty_accounts));
+
+  // Spawn account maintenance jobs
+  // (yearly requalification, point expiry, reservation and
+  // adjustment request timeout)
+  // Checks hourly whether any account is due
+  let job_accounts = loyalty_accounts.clone();
+  let job_config = config.clone();
+  tokio::task::spawn(async move {
+    let mut interval = tokio::time::interval(Duration::from_secs(3600));
+    loop {
+      interval.tick().await;
+      if let Err(e) = requalify_accounts(&job_accounts, &job_config).await {
+        println!("Error while requalifying accounts: {}", e);
+      }
+      if let Err(e) = expire_points(&job_accounts).await {
+        println!("Error while expiring points: {}", e);
+      }
+      if let Err(e) = release_expired_reservations(&job_accounts).await {
+        println!("Error while releasing reservations: {}", e);
+      }
+      if let Err(e) = expire_adjustment_requests(&job_accounts).await {
+        println!("Error while expiring adjustment requests: {}", e);
+      }
+    }
+  });
+
   let addr = env::var("SERVICE_ADDR_LOYALTY")
     .unwrap_or("[::1]:50075".into())
     .parse()
@@ -360,7 +1638,14 @@ async fn main() -> Result<(), Box<dyn Error>> {
   // Spawn the server into a runtime
   tokio::task::spawn(async move {
     Server::builder()
-      .add_service(LoyaltyServer::new(LoyaltyService::init(loyalty_accounts)))
+      .add_service(LoyaltyServer::new(LoyaltyService::init(
+        loyalty_accounts,
+        Mutex::new(loyalty_campaigns),
+        Mutex::new(card_index),
+        Mutex::new(loyalty_card_batches),
+        Mutex::new(loyalty_erased_cards),
+        config,
+      )))
       .serve_with_shutdown(addr, async {
         let _ = rx.await;
       })
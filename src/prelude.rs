@@ -1,4 +1,11 @@
-use gzlib::proto::loyalty::{transaction::TransactionKind, Account, Transaction};
+use crate::campaign::CampaignReward;
+use crate::loyalty::AccountExt;
+use crate::loyalty::AdjustmentStatus;
+use chrono::{Datelike, Utc};
+use gzlib::proto::loyalty::{
+  transaction::TransactionKind, Account, AccountCard, AdjustmentRequest, AdjustmentResult,
+  Campaign, CardBatch, Reservation, Transaction, TransferResult,
+};
 
 pub enum ServiceError {
   InternalError(String),
@@ -72,25 +79,89 @@ impl From<std::env::VarError> for ServiceError {
 
 impl From<crate::loyalty::Account> for Account {
   fn from(f: crate::loyalty::Account) -> Self {
+    let year = Utc::today().year();
+    let yearly_gross_turnover = f.get_gross_turnover_of_year(year);
+    let previous_year_gross_turnover = f.get_gross_turnover_of_year(year - 1);
+    let now = Utc::now();
+    let spendable_points = f.get_spendable_balance();
+    let balance_points = f.get_balance();
+    let available_points = f.get_available_balance(now);
+    let pending_points = f.get_pending_balance(now);
     Self {
       account_id: f.account_id.to_string(),
-      customer_id: f.customer_id,
-      customer_birthdate: f.customer_birthdate.to_string(),
-      card_id: match f.card_id {
-        Some(card_id) => card_id,
+      // Empty for anonymous accounts
+      customer_id: f.customer_id.unwrap_or(0),
+      customer_birthdate: f
+        .customer_birthdate
+        .map(|d| d.to_string())
+        .unwrap_or_default(),
+      card_id: match f.get_active_card() {
+        Some(card) => card.card_id.clone(),
         None => "".to_string(),
       },
+      cards: f.cards.iter().map(|c| c.clone().into()).collect(),
       loyalty_level: f.loyalty_level.to_string(),
-      balance_points: f.balance_points,
-      yearly_gross_turnover: f.yearly_gross_turnover,
+      // Set by the service from the tier table
+      loyalty_level_name: "".to_string(),
+      balance_points,
+      available_points,
+      pending_points,
+      // Set by the service for household members
+      household_balance_points: balance_points,
+      household_id: f.household_id.map(|id| id.to_string()).unwrap_or_default(),
+      merged_into: f.merged_into.map(|id| id.to_string()).unwrap_or_default(),
+      status: f.status.to_string(),
+      status_reason: f
+        .status_changes
+        .last()
+        .map(|c| c.reason.clone())
+        .unwrap_or_default(),
+      spendable_points,
+      yearly_gross_turnover,
+      previous_year_gross_turnover,
       created_at: f.created_at.to_rfc3339(),
       created_by: f.created_by,
     }
   }
 }
 
+impl From<crate::loyalty::Card> for AccountCard {
+  fn from(f: crate::loyalty::Card) -> Self {
+    Self {
+      card_id: f.card_id,
+      state: f.state.to_string(),
+      reason: f.reason,
+      issued_at: f.issued_at.to_rfc3339(),
+      retired_at: f.retired_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+    }
+  }
+}
+
 impl From<crate::loyalty::Transaction> for Transaction {
   fn from(f: crate::loyalty::Transaction) -> Self {
+    // Kind specific details
+    let (campaign_id, reason, notes) = match &f.transaction_kind {
+      crate::loyalty::TransactionKind::Earn {
+        campaign_id: Some(campaign_id),
+        ..
+      } => (campaign_id.to_string(), "".to_string(), "".to_string()),
+      crate::loyalty::TransactionKind::Adjustment { reason, notes, .. } => {
+        ("".to_string(), reason.to_string(), notes.clone())
+      }
+      _ => ("".to_string(), "".to_string(), "".to_string()),
+    };
+    let transfer_id = match f.transaction_kind {
+      crate::loyalty::TransactionKind::TransferOut { transfer_id, .. }
+      | crate::loyalty::TransactionKind::TransferIn { transfer_id, .. } => transfer_id.to_string(),
+      _ => "".to_string(),
+    };
+    // Taken points are shown as negative amount
+    let amount = match f.transaction_kind {
+      crate::loyalty::TransactionKind::Adjustment {
+        is_credit: false, ..
+      } => -f.amount.value(),
+      _ => f.amount.value(),
+    };
     Self {
       transaction_id: f.transaction_id.to_string(),
       account_id: f.account_id.to_string(),
@@ -98,13 +169,148 @@ impl From<crate::loyalty::Transaction> for Transaction {
       transaction_kind: match f.transaction_kind {
         crate::loyalty::TransactionKind::Earn {
           total_payable_amount: _,
-          discount: _,
+          rate: _,
+          rounding: _,
+          campaign_id: _,
         } => TransactionKind::Earn,
         crate::loyalty::TransactionKind::Burn => TransactionKind::Burn,
+        crate::loyalty::TransactionKind::Expire { lot_id: _ } => TransactionKind::Expire,
+        crate::loyalty::TransactionKind::Refund {
+          earn_transaction_id: _,
+          refund_amount: _,
+          rounding: _,
+        } => TransactionKind::Refund,
+        crate::loyalty::TransactionKind::BurnCancel {
+          burn_transaction_ids: _,
+        } => TransactionKind::BurnCancel,
+        crate::loyalty::TransactionKind::Birthday { year: _ } => TransactionKind::Birthday,
+        crate::loyalty::TransactionKind::BirthdayRefund {
+          birthday_transaction_id: _,
+        } => TransactionKind::Refund,
+        crate::loyalty::TransactionKind::Adjustment {
+          reason: _,
+          notes: _,
+          is_credit: _,
+        } => TransactionKind::Adjustment,
+        crate::loyalty::TransactionKind::TransferOut {
+          transfer_id: _,
+          to_account_id: _,
+          pooled: _,
+        } => TransactionKind::TransferOut,
+        crate::loyalty::TransactionKind::TransferIn {
+          transfer_id: _,
+          from_account_id: _,
+        } => TransactionKind::TransferIn,
       } as i32,
-      amount: f.amount,
+      amount,
       created_by: f.crated_by,
       created_at: f.created_at.to_rfc3339(),
+      campaign_id,
+      reason,
+      notes,
+      transfer_id,
+    }
+  }
+}
+
+impl From<crate::loyalty::Reservation> for Reservation {
+  fn from(f: crate::loyalty::Reservation) -> Self {
+    Self {
+      reservation_id: f.reservation_id.to_string(),
+      purchase_id: f.purchase_id.to_string(),
+      amount: f.amount.value(),
+      created_by: f.created_by,
+      created_at: f.created_at.to_rfc3339(),
+      expires_at: f.expires_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<crate::campaign::Campaign> for Campaign {
+  fn from(f: crate::campaign::Campaign) -> Self {
+    let (multiplier_percentage, bonus_points) = match f.reward {
+      CampaignReward::Multiplier { percentage } => (percentage, 0),
+      CampaignReward::Bonus { points } => (0, points),
+    };
+    Self {
+      campaign_id: f.campaign_id.to_string(),
+      name: f.name,
+      start_date: f.start_date.to_string(),
+      end_date: f.end_date.to_string(),
+      weekdays: f.weekdays.iter().map(|d| d.number_from_monday()).collect(),
+      loyalty_levels: f.loyalty_levels.iter().map(|l| l.to_string()).collect(),
+      multiplier_percentage,
+      bonus_points,
+      created_by: f.created_by,
+      created_at: f.created_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<crate::card::CardBatch> for CardBatch {
+  fn from(f: crate::card::CardBatch) -> Self {
+    Self {
+      batch_id: f.batch_id.to_string(),
+      card_ids: f.card_ids,
+      status: format!("{:?}", f.status),
+      created_by: f.created_by,
+      created_at: f.created_at.to_rfc3339(),
+      printed_at: f.printed_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+      activated_at: f.activated_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+    }
+  }
+}
+
+impl From<crate::loyalty::AdjustmentRequest> for AdjustmentRequest {
+  fn from(f: crate::loyalty::AdjustmentRequest) -> Self {
+    let transaction_id = match f.status {
+      AdjustmentStatus::Approved { transaction_id } => transaction_id.to_string(),
+      _ => "".to_string(),
+    };
+    Self {
+      request_id: f.request_id.to_string(),
+      account_id: f.account_id.to_string(),
+      amount: f.amount,
+      reason: f.reason.to_string(),
+      notes: f.notes,
+      status: match f.status {
+        AdjustmentStatus::Pending => "Pending",
+        AdjustmentStatus::Approved { .. } => "Approved",
+        AdjustmentStatus::Rejected => "Rejected",
+        AdjustmentStatus::Expired => "Expired",
+      }
+      .to_string(),
+      transaction_id,
+      requested_by: f.requested_by,
+      requested_at: f.requested_at.to_rfc3339(),
+      decided_by: f.decided_by.unwrap_or(0),
+      decided_at: f.decided_at.map(|d| d.to_rfc3339()).unwrap_or_default(),
+      expires_at: f.expires_at.to_rfc3339(),
+    }
+  }
+}
+
+impl From<crate::loyalty::AdjustmentOutcome> for AdjustmentResult {
+  fn from(f: crate::loyalty::AdjustmentOutcome) -> Self {
+    match f {
+      crate::loyalty::AdjustmentOutcome::Applied(transaction) => Self {
+        transaction: Some(transaction.into()),
+        pending_request: None,
+      },
+      crate::loyalty::AdjustmentOutcome::Pending(request) => Self {
+        transaction: None,
+        pending_request: Some(request.into()),
+      },
+    }
+  }
+}
+
+impl From<crate::loyalty::Transfer> for TransferResult {
+  fn from(f: crate::loyalty::Transfer) -> Self {
+    Self {
+      transfer_id: f.transfer_id.to_string(),
+      debit: Some(f.debit.into()),
+      credit: Some(f.credit.into()),
     }
   }
 }